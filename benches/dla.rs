@@ -0,0 +1,36 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use procedural_terrain::sim::r#gen::dla::{self, DiffusionLimitedAggregationParams, Point};
+
+fn params(size: usize, layers: u32) -> DiffusionLimitedAggregationParams {
+    DiffusionLimitedAggregationParams {
+        width: size,
+        height: size,
+        spawns: vec![Point::new(size as u32 / 2, size as u32 / 2)],
+        layers,
+        seed: 1,
+        ..Default::default()
+    }
+}
+
+fn bench_dla(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dla");
+    group.sample_size(10);
+
+    for layers in [2, 4] {
+        group.bench_function(format!("linked_32_layers_{}", layers), |b| {
+            b.iter(|| dla::generate_linked(params(32, layers)))
+        });
+        group.bench_function(format!("grid_32_layers_{}", layers), |b| {
+            b.iter(|| dla::generate(params(32, layers)))
+        });
+    }
+
+    group.bench_function("grid_64_layers_6", |b| {
+        b.iter(|| dla::generate(params(64, 6)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_dla);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use procedural_terrain::sim::r#gen::lib::{Algorithms, HeightMap};
+use procedural_terrain::sim::r#gen::perlin::{FractalPerlinParams, FractalPerlinSampler};
+
+fn params(size: usize) -> FractalPerlinParams {
+    FractalPerlinParams {
+        width: size,
+        height: size,
+        seed: 1,
+        ..Default::default()
+    }
+}
+
+// the one sample at a time loop generate used before batching
+fn generate_scalar(params: &FractalPerlinParams) -> Vec<f32> {
+    let sampler = FractalPerlinSampler::new(params);
+    let mut heights = vec![0.0; params.width * params.height];
+    for i in 0..params.height {
+        for j in 0..params.width {
+            heights[i * params.width + j] = sampler.sample(
+                i as f32 / params.height as f32,
+                j as f32 / params.width as f32,
+            );
+        }
+    }
+    heights
+}
+
+fn bench_perlin(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fractal_perlin");
+    group.sample_size(10);
+
+    for size in [4096, 8192] {
+        group.bench_function(format!("scalar_{}", size), |b| {
+            b.iter(|| generate_scalar(&params(size)))
+        });
+        group.bench_function(format!("batch_{}", size), |b| {
+            b.iter(|| HeightMap::generate(Algorithms::FractalPerlin(params(size))))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_perlin);
+criterion_main!(benches);
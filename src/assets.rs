@@ -1,5 +1,3 @@
-use cgmath::InnerSpace;
-use image;
 use std::io::{BufReader, Cursor};
 use std::path::Path;
 use std::rc::Rc;
@@ -7,6 +5,7 @@ use wgpu::util::DeviceExt;
 
 use crate::model;
 use crate::render::texture;
+use crate::sim::r#gen::io::{self, VerticalScale};
 use crate::sim::r#gen::lib::HeightMapMesh;
 
 #[cfg(target_arch = "wasm32")]
@@ -64,38 +63,15 @@ pub async fn load_texture(
     texture::Texture::from_bytes(device, queue, &data, file_name)
 }
 
-pub async fn model_from_mesh(
-    file_name: &str,
+// material with the default texture for meshes built from heightmaps, load it
+// once and share it between models rather than once per model
+pub async fn load_mesh_material(
+    name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
-    meshed: HeightMapMesh,
     materials: &mut Vec<Rc<model::Material>>,
-) -> anyhow::Result<model::Model> {
-    let vertices: Vec<model::ModelVertex> = meshed
-        .vertices
-        .into_iter()
-        .zip(meshed.uvs.into_iter())
-        .zip(meshed.normals.into_iter())
-        .map(|((pos, uv), normal)| model::ModelVertex {
-            position: [pos[2], pos[1], pos[0]],
-            tex_coords: uv,
-            normal,
-        })
-        .collect();
-
-    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Vertex Buffer"),
-        contents: bytemuck::cast_slice(&vertices),
-        usage: wgpu::BufferUsages::VERTEX,
-    });
-
-    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Index Buffer"),
-        contents: bytemuck::cast_slice(&meshed.indices),
-        usage: wgpu::BufferUsages::INDEX,
-    });
-
+) -> anyhow::Result<Rc<model::Material>> {
     let default_texture_file = "rainbow_gradient.png".to_string();
     let diffuse_texture = load_texture(&default_texture_file, device, queue).await?;
     let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -114,21 +90,56 @@ pub async fn model_from_mesh(
     });
 
     let material = Rc::new(model::Material {
-        name: file_name.to_string(),
+        name: name.to_string(),
         diffuse_texture,
         bind_group,
     });
     materials.push(material.clone());
 
-    Ok(model::Model {
+    Ok(material)
+}
+
+pub fn model_from_mesh(
+    name: &str,
+    device: &wgpu::Device,
+    meshed: HeightMapMesh,
+    material: Rc<model::Material>,
+) -> model::Model {
+    let vertices: Vec<model::ModelVertex> = meshed
+        .vertices
+        .into_iter()
+        .zip(meshed.uvs.into_iter())
+        .zip(meshed.normals.into_iter())
+        // mesh vertices are already [x, height, z], swapping the axes here would
+        // transpose the model against TerrainHeights::sample and other meshes
+        .map(|((pos, uv), normal)| model::ModelVertex {
+            position: pos,
+            tex_coords: uv,
+            normal,
+        })
+        .collect();
+
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Vertex Buffer"),
+        contents: bytemuck::cast_slice(&vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Index Buffer"),
+        contents: bytemuck::cast_slice(&meshed.indices),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+
+    model::Model {
         meshes: vec![model::Mesh {
-            name: file_name.to_string(),
+            name: name.to_string(),
             vertex_buffer,
             index_buffer,
             num_elements: meshed.indices.len() as u32,
             material,
         }],
-    })
+    }
 }
 
 pub struct TerrainHeights {
@@ -154,125 +165,22 @@ pub async fn load_heightmap_png(
 ) -> anyhow::Result<(model::Model, TerrainHeights)> {
     let png_data = load_binary(file_name).await?;
 
-    let png = image::load_from_memory(&png_data)?;
-    let png = png.to_luma16();
-    let (width, height) = png.dimensions();
-
-    let scale = 10.0;
-    let mut heights = vec![0.0f32; (width * height) as usize];
-    for z in 0..height {
-        for x in 0..width {
-            let h = png.get_pixel(x, z).0[0] as f32 / u16::MAX as f32;
-            heights[(z * width + x) as usize] = h * scale;
-        }
-    }
-
-    let mut vertices = Vec::new();
-    for z in 0..height {
-        for x in 0..width {
-            let idx = (z * width + x) as usize;
-
-            let y = heights[idx];
-
-            let u = x as f32 / (width - 1) as f32;
-            let v = z as f32 / (height - 1) as f32;
-
-            let h_l = if x > 0 {
-                heights[(z * width + (x - 1)) as usize]
-            } else {
-                y
-            };
-            let h_r = if x + 1 < width {
-                heights[(z * width + (x + 1)) as usize]
-            } else {
-                y
-            };
-            let h_d = if z > 0 {
-                heights[((z - 1) * width + x) as usize]
-            } else {
-                y
-            };
-            let h_u = if z + 1 < height {
-                heights[((z + 1) * width + x) as usize]
-            } else {
-                y
-            };
-
-            let normal = cgmath::Vector3::new(h_l - h_r, 2.0, h_d - h_u).normalize();
-
-            vertices.push(model::ModelVertex {
-                position: [x as f32, y, z as f32],
-                tex_coords: [u, v],
-                normal: [normal.x, normal.y, normal.z],
-            });
-        }
-    }
-
-    let mut indices = Vec::new();
-    for z in 0..height - 1 {
-        for x in 0..width - 1 {
-            let i0 = z * width + x;
-            let i1 = i0 + 1;
-            let i2 = i0 + width;
-            let i3 = i2 + 1;
-
-            indices.push(i0 as u32);
-            indices.push(i2 as u32);
-            indices.push(i1 as u32);
-
-            indices.push(i1 as u32);
-            indices.push(i2 as u32);
-            indices.push(i3 as u32);
-        }
-    }
-
-    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Vertex Buffer"),
-        contents: bytemuck::cast_slice(&vertices),
-        usage: wgpu::BufferUsages::VERTEX,
-    });
-
-    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Index Buffer"),
-        contents: bytemuck::cast_slice(&indices),
-        usage: wgpu::BufferUsages::INDEX,
-    });
-
-    let diffuse_texture = load_texture("rainbow_gradient.png", device, queue).await?;
-    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-            },
-        ],
-        label: None,
-    });
-
-    let material = Rc::new(model::Material {
-        name: file_name.to_string(),
-        diffuse_texture,
-        bind_group,
-    });
-    materials.push(material.clone());
+    // pngs saved from a HeightMap carry their real scale, any other png spans 0..10
+    let map = io::decode_png(
+        &png_data,
+        VerticalScale {
+            scale: 10.0,
+            offset: 0.0,
+        },
+    )?;
+    let material = load_mesh_material(file_name, device, queue, layout, materials).await?;
+    let model = model_from_mesh(file_name, device, map.to_mesh(), material);
+    let (width, height) = (map.width() as u32, map.height() as u32);
 
     Ok((
-        model::Model {
-            meshes: vec![model::Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: indices.len() as u32,
-                material,
-            }],
-        },
+        model,
         TerrainHeights {
-            heights,
+            heights: map.into_vec(),
             width,
             height,
         },
@@ -0,0 +1,271 @@
+// headless heightmap generation, runs generators or recipes without a window or gpu
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use log::info;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use procedural_terrain::sim::r#gen::dla::{
+    DiffusionLimitedAggregationMode, DiffusionLimitedAggregationParams,
+};
+use procedural_terrain::sim::r#gen::io::{self, HeightMapFormat};
+use procedural_terrain::sim::r#gen::lib::{Algorithms, HeightMap};
+use procedural_terrain::sim::r#gen::ops::HeightMapStats;
+use procedural_terrain::sim::r#gen::perlin::{FractalPerlinParams, GradientFractalPerlinParams};
+use procedural_terrain::sim::r#gen::pipeline::{Pipeline, Recipe};
+use procedural_terrain::sim::r#gen::simplex::SimplexParams;
+use procedural_terrain::sim::r#gen::worley::WorleyParams;
+
+#[derive(Parser)]
+#[command(
+    name = "terrain-gen",
+    about = "Generate heightmaps from the command line"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "out",
+        help = "Directory to write outputs to"
+    )]
+    out_dir: PathBuf,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "terrain",
+        help = "File name of every output"
+    )]
+    name: String,
+
+    #[arg(
+        short,
+        long = "format",
+        global = true,
+        value_enum,
+        default_values_t = [Format::Png16],
+        help = "Heightmap formats to write, may be repeated"
+    )]
+    formats: Vec<Format>,
+
+    #[arg(long, global = true, help = "Also write the heightmap mesh as an obj")]
+    mesh: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Evaluate a YAML or RON terrain recipe")]
+    Recipe {
+        file: PathBuf,
+
+        #[arg(long, help = "Output this node instead of the recipe's output")]
+        node: Option<String>,
+    },
+    #[command(about = "Run a single generator")]
+    Generate {
+        algorithm: Algorithm,
+
+        #[arg(
+            short,
+            long,
+            help = "YAML or RON file of generator params, defaults otherwise"
+        )]
+        params: Option<PathBuf>,
+
+        #[arg(short, long, help = "Overrides the seed in the params")]
+        seed: Option<u32>,
+
+        #[arg(long, help = "Grayscale image seeding DLA in image mode")]
+        mask: Option<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Algorithm {
+    FractalPerlin,
+    GradientFractalPerlin,
+    Dla,
+    Simplex,
+    Worley,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Format {
+    Png16,
+    Tiff,
+    Exr,
+    R16,
+    R32,
+}
+
+impl From<Format> for HeightMapFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Png16 => HeightMapFormat::Png16,
+            Format::Tiff => HeightMapFormat::Tiff,
+            Format::Exr => HeightMapFormat::Exr,
+            Format::R16 => HeightMapFormat::R16,
+            Format::R32 => HeightMapFormat::R32,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Summary {
+    source: String,
+    width: usize,
+    height: usize,
+    #[serde(flatten)]
+    stats: Option<HeightMapStats>,
+    generation_seconds: f64,
+    outputs: Vec<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+
+    let start = Instant::now();
+    // exact gradients for the mesh normals when the generator provides them
+    let mut gradient = None;
+    let (map, source) = match &cli.command {
+        Command::Recipe { file, node } => {
+            let mut recipe = Recipe::load(file)?;
+            if let Some(node) = node {
+                recipe.output = node.clone();
+            }
+            let map = Pipeline::new().evaluate(&recipe)?;
+            (
+                HeightMap::clone(&map),
+                format!("{}:{}", file.display(), recipe.output),
+            )
+        }
+        Command::Generate {
+            algorithm,
+            params,
+            seed,
+            mask,
+        } => {
+            let algorithm =
+                algorithm_params(*algorithm, params.as_deref(), *seed, mask.as_deref())?;
+            let source = match &algorithm {
+                Algorithms::FractalPerlin(_) => "fractal_perlin",
+                Algorithms::GradientFractalPerlin(_) => "gradient_fractal_perlin",
+                Algorithms::DiffusionLimitedAggregation(_) => "dla",
+                Algorithms::Simplex(_) => "simplex",
+                Algorithms::Worley(_) => "worley",
+            };
+            if cli.mesh
+                && let Algorithms::FractalPerlin(params) = &algorithm
+            {
+                gradient = Some(HeightMap::generate_with_gradient(params.clone()).1);
+            }
+            (HeightMap::generate(algorithm), source.to_string())
+        }
+    };
+    let generation_seconds = start.elapsed().as_secs_f64();
+    info!(
+        "Generated {}x{} heightmap in {:.2}s",
+        map.width(),
+        map.height(),
+        generation_seconds
+    );
+
+    std::fs::create_dir_all(&cli.out_dir)
+        .with_context(|| format!("Failed to create {}", cli.out_dir.display()))?;
+    let mut outputs = Vec::new();
+    for &format in &cli.formats {
+        let format = HeightMapFormat::from(format);
+        let path = cli
+            .out_dir
+            .join(format!("{}.{}", cli.name, format.extension()));
+        map.save_as(&path, format)?;
+        info!("Wrote {}", path.display());
+        outputs.push(path);
+    }
+    if cli.mesh {
+        let path = cli.out_dir.join(format!("{}.obj", cli.name));
+        let mesh = match &gradient {
+            Some(gradient) => map.to_mesh_with_gradient(gradient)?,
+            None => map.to_mesh(),
+        };
+        io::save_obj(&mesh, &path)?;
+        info!("Wrote {}", path.display());
+        outputs.push(path);
+    }
+
+    let summary = Summary {
+        source,
+        width: map.width(),
+        height: map.height(),
+        stats: map.stats(),
+        generation_seconds,
+        outputs,
+    };
+    let path = cli.out_dir.join(format!("{}.json", cli.name));
+    std::fs::write(&path, serde_json::to_string_pretty(&summary)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("Wrote {}", path.display());
+
+    Ok(())
+}
+
+fn algorithm_params(
+    algorithm: Algorithm,
+    path: Option<&Path>,
+    seed: Option<u32>,
+    mask: Option<&Path>,
+) -> Result<Algorithms> {
+    Ok(match algorithm {
+        Algorithm::FractalPerlin => {
+            let mut params: FractalPerlinParams = load_params(path)?;
+            params.seed = seed.unwrap_or(params.seed);
+            Algorithms::FractalPerlin(params)
+        }
+        Algorithm::GradientFractalPerlin => {
+            let mut params: GradientFractalPerlinParams = load_params(path)?;
+            params.seed = seed.unwrap_or(params.seed);
+            Algorithms::GradientFractalPerlin(params)
+        }
+        Algorithm::Dla => {
+            let mut params: DiffusionLimitedAggregationParams = load_params(path)?;
+            params.seed = seed.unwrap_or(params.seed);
+            if let Some(mask) = mask {
+                params.mode = DiffusionLimitedAggregationMode::from_image(mask)?;
+            }
+            Algorithms::DiffusionLimitedAggregation(params)
+        }
+        Algorithm::Simplex => {
+            let mut params: SimplexParams = load_params(path)?;
+            params.seed = seed.unwrap_or(params.seed);
+            Algorithms::Simplex(params)
+        }
+        Algorithm::Worley => {
+            let mut params: WorleyParams = load_params(path)?;
+            params.seed = seed.unwrap_or(params.seed);
+            Algorithms::Worley(params)
+        }
+    })
+}
+
+fn load_params<T: DeserializeOwned + Default>(path: Option<&Path>) -> Result<T> {
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let params = match path.extension().and_then(|e| e.to_str()) {
+        Some("ron") => ron::from_str(&text).map_err(anyhow::Error::from),
+        _ => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+    };
+    params.with_context(|| format!("Failed to parse {}", path.display()))
+}
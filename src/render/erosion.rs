@@ -0,0 +1,516 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use wgpu::util::DeviceExt;
+
+use super::pipeline::create_compute_pipeline;
+use crate::sim::erosion::hydraulic::{self, HydraulicErosionParams};
+use crate::sim::r#gen::lib::HeightMap;
+
+const WORKGROUP_SIZE: u32 = 64;
+const FIXED_SCALE: f32 = 65536.0;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ErosionUniform {
+    width: u32,
+    height: u32,
+    droplets: u32,
+    max_lifetime: u32,
+    radius: u32,
+    inertia: f32,
+    capacity: f32,
+    min_slope: f32,
+    erosion: f32,
+    deposition: f32,
+    evaporation: f32,
+    gravity: f32,
+    initial_water: f32,
+    initial_speed: f32,
+    _padding: [u32; 2],
+}
+
+pub struct ErosionPipeline {
+    bind_group_layout: wgpu::BindGroupLayout,
+    erode_pipeline: wgpu::ComputePipeline,
+    apply_pipeline: wgpu::ComputePipeline,
+}
+
+impl ErosionUniform {
+    fn new(params: &HydraulicErosionParams, width: usize, height: usize, droplets: u32) -> Self {
+        ErosionUniform {
+            width: width as u32,
+            height: height as u32,
+            droplets,
+            max_lifetime: params.max_lifetime,
+            radius: params.radius as u32,
+            inertia: params.inertia,
+            capacity: params.capacity,
+            min_slope: params.min_slope,
+            erosion: params.erosion,
+            deposition: params.deposition,
+            evaporation: params.evaporation,
+            gravity: params.gravity,
+            initial_water: params.initial_water,
+            initial_speed: params.initial_speed,
+            _padding: [0; 2],
+        }
+    }
+}
+
+// droplet start positions for each batch, drawn in the same order as
+// hydraulic::erode and shared by the gpu and reference paths
+fn batches(
+    params: &HydraulicErosionParams,
+    batch_size: u32,
+    width: usize,
+    height: usize,
+) -> Vec<Vec<[f32; 2]>> {
+    let mut rng = StdRng::seed_from_u64(params.seed as u64);
+    let batch_size = batch_size.max(1);
+    let mut batches = Vec::new();
+    let mut remaining = params.droplets;
+
+    while remaining > 0 {
+        let count = remaining.min(batch_size);
+        let starts = (0..count)
+            .map(|_| {
+                [
+                    rng.gen_range(0.0..(width - 1) as f32),
+                    rng.gen_range(0.0..(height - 1) as f32),
+                ]
+            })
+            .collect();
+        batches.push(starts);
+        remaining -= count;
+    }
+
+    batches
+}
+
+// workgroups covering `invocations`, wrapped into rows so no dimension passes
+// the device limit. The shaders flatten the grid back into one index
+fn workgroups(device: &wgpu::Device, invocations: u32) -> (u32, u32) {
+    let groups = invocations.div_ceil(WORKGROUP_SIZE);
+    let x = groups.min(device.limits().max_compute_workgroups_per_dimension);
+    (x, groups.div_ceil(x.max(1)))
+}
+
+impl ErosionPipeline {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1, false),
+                storage(2, true),
+                storage(3, false),
+            ],
+            label: Some("erosion_bind_group_layout"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Erosion Pipeline Layout"),
+            bind_group_layouts: &[Some(&bind_group_layout)],
+            immediate_size: 0,
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Erosion Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                include_str!("../shaders/compute/erosion.wgsl").into(),
+            ),
+        });
+
+        Self {
+            erode_pipeline: create_compute_pipeline(device, &layout, &shader, "erode_main"),
+            apply_pipeline: create_compute_pipeline(device, &layout, &shader, "apply_main"),
+            bind_group_layout,
+        }
+    }
+
+    pub fn run(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        map: &HeightMap,
+        params: &HydraulicErosionParams,
+        batch_size: u32,
+    ) -> anyhow::Result<HeightMap> {
+        let (width, height) = (map.width(), map.height());
+        if width < 2 || height < 2 {
+            return Ok(map.clone());
+        }
+
+        let heights = map.as_slice();
+        let size = std::mem::size_of_val(heights) as wgpu::BufferAddress;
+        let batch_size = batch_size.clamp(1, params.droplets.max(1));
+        let start_size = batch_size as u64 * std::mem::size_of::<[f32; 2]>() as u64;
+        let limits = device.limits();
+        for (name, bytes) in [("height", size), ("droplet start", start_size)] {
+            anyhow::ensure!(
+                bytes <= limits.max_storage_buffer_binding_size && bytes <= limits.max_buffer_size,
+                "{} buffer of {} bytes for a {}x{} map is over the device limit of {} bytes",
+                name,
+                bytes,
+                width,
+                height,
+                limits
+                    .max_storage_buffer_binding_size
+                    .min(limits.max_buffer_size)
+            );
+        }
+
+        let height_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Erosion Height Buffer"),
+            contents: bytemuck::cast_slice(heights),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        });
+        let delta_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Erosion Delta Buffer"),
+            contents: bytemuck::cast_slice(&vec![0i32; heights.len()]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let start_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Erosion Start Buffer"),
+            size: start_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Erosion Params Buffer"),
+            size: std::mem::size_of::<ErosionUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Erosion Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: height_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: start_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: delta_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("erosion_bind_group"),
+        });
+
+        let cells = (width * height) as u32;
+        for starts in batches(params, batch_size, width, height) {
+            let uniform = ErosionUniform::new(params, width, height, starts.len() as u32);
+            queue.write_buffer(&uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+            queue.write_buffer(&start_buffer, 0, bytemuck::cast_slice(&starts));
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Erosion Encoder"),
+            });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Erosion Pass"),
+                    timestamp_writes: None,
+                });
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.set_pipeline(&self.erode_pipeline);
+                let (x, y) = workgroups(device, starts.len() as u32);
+                pass.dispatch_workgroups(x, y, 1);
+            }
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Erosion Apply Pass"),
+                    timestamp_writes: None,
+                });
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.set_pipeline(&self.apply_pipeline);
+                let (x, y) = workgroups(device, cells);
+                pass.dispatch_workgroups(x, y, 1);
+            }
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Erosion Readback Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&height_buffer, 0, &readback_buffer, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::PollType::wait_indefinitely())?;
+        receiver.recv()??;
+
+        let result = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+        readback_buffer.unmap();
+
+        HeightMap::from_vec(width, height, result)
+    }
+}
+
+pub async fn headless_device() -> anyhow::Result<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all().with_env(),
+        flags: wgpu::InstanceFlags::default(),
+        backend_options: wgpu::BackendOptions::default(),
+        display: None,
+        memory_budget_thresholds: wgpu::MemoryBudgetThresholds::default(),
+    });
+
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter: false,
+        })
+        .await?;
+
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            label: Some("Headless Device"),
+            required_features: wgpu::Features::empty(),
+            experimental_features: wgpu::ExperimentalFeatures::disabled(),
+            required_limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+            memory_hints: Default::default(),
+            trace: wgpu::Trace::Off,
+        })
+        .await?;
+
+    Ok((device, queue))
+}
+
+// cpu implementation of erosion.wgsl. Droplets in a batch are traced in order
+// here but run together on the gpu, so a batch_size of 1 is the only case where
+// both follow the same droplets. Even then the gpu can round differently, which
+// long droplet paths can grow into visible differences
+pub fn erode_reference(
+    map: &HeightMap,
+    params: &HydraulicErosionParams,
+    batch_size: u32,
+) -> HeightMap {
+    let (width, height) = (map.width(), map.height());
+    if width < 2 || height < 2 {
+        return map.clone();
+    }
+
+    let mut heights = map.as_slice().to_vec();
+    let brush = hydraulic::brush(params.radius);
+    let mut deltas = vec![0i32; heights.len()];
+    for starts in batches(params, batch_size, width, height) {
+        for start in starts {
+            trace_droplet(&heights, &mut deltas, width, height, &brush, params, start);
+        }
+
+        for (h, d) in heights.iter_mut().zip(deltas.iter_mut()) {
+            *h += *d as f32 / FIXED_SCALE;
+            *d = 0;
+        }
+    }
+
+    HeightMap::from_vec(width, height, heights).unwrap()
+}
+
+fn to_fixed(value: f32) -> i32 {
+    (value * FIXED_SCALE + 0.5).floor() as i32
+}
+
+fn trace_droplet(
+    heights: &[f32],
+    deltas: &mut [i32],
+    width: usize,
+    height: usize,
+    brush: &[(i32, i32, f32)],
+    params: &HydraulicErosionParams,
+    start: [f32; 2],
+) {
+    let (mut x, mut z) = (start[0], start[1]);
+    let (mut dir_x, mut dir_z) = (0.0f32, 0.0f32);
+    let mut speed = params.initial_speed;
+    let mut water = params.initial_water;
+    let mut sediment = 0.0f32;
+    let height_at = |deltas: &[i32], x: f32, z: f32| {
+        hydraulic::height_and_gradient(width, x, z, |i| heights[i] + deltas[i] as f32 / FIXED_SCALE)
+    };
+
+    for _ in 0..params.max_lifetime {
+        let (old_x, old_z) = (x, z);
+        let (old_height, grad_x, grad_z) = height_at(deltas, old_x, old_z);
+
+        dir_x = dir_x * params.inertia - grad_x * (1.0 - params.inertia);
+        dir_z = dir_z * params.inertia - grad_z * (1.0 - params.inertia);
+        let len = (dir_x * dir_x + dir_z * dir_z).sqrt();
+        if len <= f32::EPSILON {
+            break;
+        }
+        dir_x /= len;
+        dir_z /= len;
+        x += dir_x;
+        z += dir_z;
+
+        if x < 0.0 || z < 0.0 || x >= (width - 1) as f32 || z >= (height - 1) as f32 {
+            return;
+        }
+
+        let delta = height_at(deltas, x, z).0 - old_height;
+        let capacity = (-delta).max(params.min_slope) * speed * water * params.capacity;
+
+        if sediment > capacity || delta > 0.0 {
+            let amount = if delta > 0.0 {
+                delta.min(sediment)
+            } else {
+                (sediment - capacity) * params.deposition
+            };
+            sediment -= amount;
+            deposit(deltas, width, old_x, old_z, amount);
+        } else {
+            let amount = ((capacity - sediment) * params.erosion).min(-delta);
+            sediment += erode(deltas, width, height, brush, old_x, old_z, amount);
+        }
+
+        speed = (speed * speed - delta * params.gravity).max(0.0).sqrt();
+        water *= 1.0 - params.evaporation;
+    }
+
+    deposit(deltas, width, x, z, sediment);
+}
+
+fn deposit(deltas: &mut [i32], width: usize, x: f32, z: f32, amount: f32) {
+    for (x, z, weight) in hydraulic::corners(x, z) {
+        deltas[z * width + x] += to_fixed(amount * weight);
+    }
+}
+
+// removes `amount` spread over the brush and returns what was taken
+fn erode(
+    deltas: &mut [i32],
+    width: usize,
+    height: usize,
+    brush: &[(i32, i32, f32)],
+    x: f32,
+    z: f32,
+    amount: f32,
+) -> f32 {
+    let (cells, total) = hydraulic::brush_cells(brush, width, height, x, z);
+    if total <= 0.0 {
+        return 0.0;
+    }
+
+    for (x, z, weight) in cells {
+        deltas[z * width + x] -= to_fixed(amount * weight / total);
+    }
+
+    amount
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> Option<(wgpu::Device, wgpu::Queue)> {
+        // skipped on machines without any adapter, software ones included
+        let device = pollster::block_on(headless_device()).ok();
+        if device.is_none() {
+            eprintln!("no wgpu adapter, skipping");
+        }
+        device
+    }
+
+    fn hills(width: usize, height: usize) -> HeightMap {
+        let heights = (0..width * height)
+            .map(|i| {
+                let (x, z) = ((i % width) as f32, (i / width) as f32);
+                8.0 - x * 0.1 + (z * 0.4).sin() + (x * 0.3).cos() * 0.5
+            })
+            .collect();
+        HeightMap::from_vec(width, height, heights).unwrap()
+    }
+
+    // the gpu may round differently (fused multiply adds), which moves a
+    // fixed point delta by a unit. Droplets feel their own erosion, so over long
+    // paths or many droplets that unit grows into a different path. Short single
+    // droplets are compared instead
+    #[test]
+    fn gpu_matches_reference() {
+        let Some((device, queue)) = device() else {
+            return;
+        };
+        let pipeline = ErosionPipeline::new(&device);
+        let map = hills(48, 40);
+
+        for seed in 0..64 {
+            let params = HydraulicErosionParams {
+                seed,
+                droplets: 1,
+                max_lifetime: 10,
+                ..Default::default()
+            };
+            let gpu = pipeline.run(&device, &queue, &map, &params, 1).unwrap();
+            let reference = erode_reference(&map, &params, 1);
+
+            for (i, (g, r)) in gpu.iter().zip(reference.iter()).enumerate() {
+                assert!(
+                    (g - r).abs() < 1e-3,
+                    "seed {} cell {}: gpu {} reference {}",
+                    seed,
+                    i,
+                    g,
+                    r
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dispatches_past_workgroup_limit() {
+        let Some((device, queue)) = device() else {
+            return;
+        };
+        // 2048 * 2048 cells need 65536 workgroups of 64 in the apply pass
+        let map = hills(2048, 2048);
+        let params = HydraulicErosionParams {
+            droplets: 16,
+            ..Default::default()
+        };
+
+        let gpu = ErosionPipeline::new(&device)
+            .run(&device, &queue, &map, &params, 16)
+            .unwrap();
+        assert!(gpu.iter().zip(map.iter()).any(|(g, h)| g != h));
+    }
+}
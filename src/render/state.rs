@@ -3,6 +3,7 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use super::camera;
+use super::erosion::ErosionPipeline;
 use super::model;
 use super::model::{DrawLight, Vertex};
 use super::pipeline::create_render_pipeline;
@@ -10,6 +11,9 @@ use super::scene::{self, Object};
 use super::texture;
 use super::transform::{Transform, TransformRaw};
 use crate::assets;
+use crate::sim::erosion::hydraulic::HydraulicErosionParams;
+use crate::sim::r#gen::lib::HeightMap;
+use crate::sim::hydrology::depression;
 use cgmath::prelude::*;
 use wgpu::util::DeviceExt;
 use winit::event::MouseButton;
@@ -19,6 +23,7 @@ use winit::window::Window;
 
 const GUI_UPDATE_RATE: f32 = 1.0 / 2.0;
 const LIGHT_ORBIT_DEG_PER_SEC: f32 = 15.0;
+const EROSION_BATCH_SIZE: u32 = 256;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -206,6 +211,12 @@ struct RenderState {
     instance_buffer: wgpu::Buffer,
     instance_capacity: u32,
     terrain_model: Rc<model::Model>,
+    water_pipeline: Rc<wgpu::RenderPipeline>,
+    // lakes filling the terrain's depressions, None when it has none
+    water_model: Option<Rc<model::Model>>,
+    // shared by every rebuild of the terrain and lake meshes
+    terrain_material: Rc<model::Material>,
+    water_material: Rc<model::Material>,
     tree_model: Rc<model::Model>,
     materials: Vec<Rc<model::Material>>,
 }
@@ -276,8 +287,41 @@ impl RenderState {
             &mut materials,
         )
         .await?;
+        let terrain_material = terrain_model.meshes[0].material.clone();
         let terrain_model = Rc::new(terrain_model);
 
+        let water_pipeline = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Water Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/water.wgsl").into()),
+            };
+
+            create_render_pipeline(
+                device,
+                &render_pipeline_layout,
+                config.format,
+                Some(texture::Texture::DEPTH_FORMAT),
+                &[model::ModelVertex::desc(), TransformRaw::desc()],
+                shader,
+            )
+        };
+        let water_pipeline = Rc::new(water_pipeline);
+
+        let (width, height) = (
+            terrain_heights.width as usize,
+            terrain_heights.height as usize,
+        );
+        let terrain_map = HeightMap::from_vec(width, height, terrain_heights.heights.clone())?;
+        let water_material = assets::load_mesh_material(
+            "water",
+            device,
+            queue,
+            texture_bind_group_layout,
+            &mut materials,
+        )
+        .await?;
+        let water_model = water_model(device, &terrain_map, &water_material).map(Rc::new);
+
         let tree_model = assets::load_obj_model(
             "tree.obj",
             device,
@@ -299,6 +343,10 @@ impl RenderState {
                 instance_buffer,
                 instance_capacity,
                 terrain_model,
+                water_pipeline,
+                water_model,
+                terrain_material,
+                water_material,
                 tree_model,
                 materials,
             },
@@ -307,6 +355,26 @@ impl RenderState {
     }
 }
 
+// lakes filling the depressions of a heightmap, None when it has none
+fn water_model(
+    device: &wgpu::Device,
+    map: &HeightMap,
+    material: &Rc<model::Material>,
+) -> Option<model::Model> {
+    let lakes = depression::find_lakes(map);
+    if lakes.is_empty() {
+        return None;
+    }
+
+    let mesh = depression::water_mesh(&lakes, map.width(), map.height());
+    Some(assets::model_from_mesh(
+        "water",
+        device,
+        mesh,
+        material.clone(),
+    ))
+}
+
 struct GuiState {
     egui_ctx: egui::Context,
     egui_state: egui_winit::State,
@@ -346,6 +414,11 @@ pub struct State {
     is_surface_configured: bool,
     pub(crate) window: Arc<Window>,
     scene: scene::Scene,
+    terrain_heights: assets::TerrainHeights,
+    terrain_entity: scene::EntityId,
+    water_entity: Option<scene::EntityId>,
+    erosion: ErosionPipeline,
+    erosion_runs: u32,
     camera: CameraState,
     render: RenderState,
     light: LightState,
@@ -508,13 +581,22 @@ impl State {
         )
         .await?;
 
-        scene.spawn(Object {
+        let terrain_entity = scene.spawn(Object {
             model: render_state.terrain_model.clone(),
             pipeline: render_state.terrain_pipeline.clone(),
             material: None,
             transform: Transform::identity(),
         });
 
+        let water_entity = render_state.water_model.as_ref().map(|water_model| {
+            scene.spawn(Object {
+                model: water_model.clone(),
+                pipeline: render_state.water_pipeline.clone(),
+                material: None,
+                transform: Transform::identity(),
+            })
+        });
+
         {
             use rand::Rng;
             let mut rng = rand::thread_rng();
@@ -568,6 +650,7 @@ impl State {
         }
 
         let gui_state = GuiState::new(&device, &config, &window);
+        let erosion = ErosionPipeline::new(&device);
 
         let mut state = Self {
             surface,
@@ -577,6 +660,11 @@ impl State {
             is_surface_configured: false,
             window,
             scene,
+            terrain_heights,
+            terrain_entity,
+            water_entity,
+            erosion,
+            erosion_runs: 0,
             camera: camera_state,
             render: render_state,
             light: light_state,
@@ -757,6 +845,7 @@ impl State {
                 .resizable(false)
                 .show(ctx, |ui| {
                     ui.label(format!("FPS: {:.2}", self.fps));
+                    ui.label(format!("Erosion runs: {} (E to erode)", self.erosion_runs));
                 });
         });
 
@@ -856,11 +945,67 @@ impl State {
     ) {
         if code == KeyCode::Escape && is_pressed {
             event_loop.exit();
+        } else if code == KeyCode::KeyE && is_pressed {
+            if let Err(e) = self.erode_terrain() {
+                log::error!("Unable to erode terrain {}", e);
+            }
         } else {
             self.camera.camera_controller.handle_key(code, is_pressed);
         }
     }
 
+    // runs hydraulic erosion over the terrain on the gpu and rebuilds the terrain
+    // and lake meshes from the result, each run with the next seed
+    fn erode_terrain(&mut self) -> anyhow::Result<()> {
+        let params = HydraulicErosionParams {
+            seed: self.erosion_runs,
+            ..Default::default()
+        };
+        self.erosion_runs += 1;
+
+        let (width, height) = (
+            self.terrain_heights.width as usize,
+            self.terrain_heights.height as usize,
+        );
+        let map = HeightMap::from_vec(width, height, self.terrain_heights.heights.clone())?;
+        let map = self
+            .erosion
+            .run(&self.device, &self.queue, &map, &params, EROSION_BATCH_SIZE)?;
+        self.terrain_heights.heights = map.as_slice().to_vec();
+
+        let terrain_model = assets::model_from_mesh(
+            "terrain",
+            &self.device,
+            map.to_mesh(),
+            self.render.terrain_material.clone(),
+        );
+        let water_model = water_model(&self.device, &map, &self.render.water_material);
+
+        self.render.terrain_model = Rc::new(terrain_model);
+        self.render.water_model = water_model.map(Rc::new);
+
+        self.scene.despawn(self.terrain_entity);
+        self.terrain_entity = self.scene.spawn(Object {
+            model: self.render.terrain_model.clone(),
+            pipeline: self.render.terrain_pipeline.clone(),
+            material: None,
+            transform: Transform::identity(),
+        });
+        if let Some(water_entity) = self.water_entity.take() {
+            self.scene.despawn(water_entity);
+        }
+        self.water_entity = self.render.water_model.as_ref().map(|water_model| {
+            self.scene.spawn(Object {
+                model: water_model.clone(),
+                pipeline: self.render.water_pipeline.clone(),
+                material: None,
+                transform: Transform::identity(),
+            })
+        });
+
+        Ok(())
+    }
+
     pub(crate) fn handle_mouse_button(&mut self, button: MouseButton, is_pressed: bool) {
         self.camera
             .camera_controller
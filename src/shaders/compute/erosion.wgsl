@@ -0,0 +1,189 @@
+// Hydraulic droplet erosion
+//
+// Droplets accumulate their height changes into a fixed point delta buffer and
+// sample heights with the pending deltas applied, so each droplet sees its own
+// erosion. The apply pass folds the deltas back into the heights between batches.
+
+const FIXED_SCALE: f32 = 65536.0;
+
+struct Params {
+    width: u32,
+    height: u32,
+    droplets: u32,
+    max_lifetime: u32,
+    radius: u32,
+    inertia: f32,
+    capacity: f32,
+    min_slope: f32,
+    erosion: f32,
+    deposition: f32,
+    evaporation: f32,
+    gravity: f32,
+    initial_water: f32,
+    initial_speed: f32,
+    _padding: vec2<u32>,
+}
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+@group(0) @binding(1)
+var<storage, read_write> heights: array<f32>;
+
+@group(0) @binding(2)
+var<storage, read> starts: array<vec2<f32>>;
+
+@group(0) @binding(3)
+var<storage, read_write> deltas: array<atomic<i32>>;
+
+fn to_fixed(value: f32) -> i32 {
+    return i32(floor(value * FIXED_SCALE + 0.5));
+}
+
+fn sample(i: u32) -> f32 {
+    return heights[i] + f32(atomicLoad(&deltas[i])) / FIXED_SCALE;
+}
+
+// bilinear height (x) and gradient (yz) at a position inside the grid
+fn height_and_gradient(pos: vec2<f32>) -> vec3<f32> {
+    let cell = vec2<u32>(floor(pos));
+    let uv = pos - floor(pos);
+
+    let i = cell.y * params.width + cell.x;
+    let h00 = sample(i);
+    let h10 = sample(i + 1u);
+    let h01 = sample(i + params.width);
+    let h11 = sample(i + params.width + 1u);
+
+    let grad_x = (h10 - h00) * (1.0 - uv.y) + (h11 - h01) * uv.y;
+    let grad_z = (h01 - h00) * (1.0 - uv.x) + (h11 - h10) * uv.x;
+    let h = h00 * (1.0 - uv.x) * (1.0 - uv.y) + h10 * uv.x * (1.0 - uv.y) + h01 * (1.0 - uv.x) * uv.y + h11 * uv.x * uv.y;
+
+    return vec3<f32>(h, grad_x, grad_z);
+}
+
+fn deposit(pos: vec2<f32>, amount: f32) {
+    let cell = vec2<u32>(floor(pos));
+    let uv = pos - floor(pos);
+    let i = cell.y * params.width + cell.x;
+
+    atomicAdd(&deltas[i], to_fixed(amount * (1.0 - uv.x) * (1.0 - uv.y)));
+    atomicAdd(&deltas[i + 1u], to_fixed(amount * uv.x * (1.0 - uv.y)));
+    atomicAdd(&deltas[i + params.width], to_fixed(amount * (1.0 - uv.x) * uv.y));
+    atomicAdd(&deltas[i + params.width + 1u], to_fixed(amount * uv.x * uv.y));
+}
+
+fn brush_weight(dx: i32, dz: i32) -> f32 {
+    if params.radius == 0u {
+        return select(0.0, 1.0, dx == 0 && dz == 0);
+    }
+
+    let radius = f32(params.radius);
+    let dist = sqrt(f32(dx * dx + dz * dz));
+    if dist < radius {
+        return 1.0 - dist / radius;
+    }
+    return 0.0;
+}
+
+// removes `amount` spread over the brush and returns what was taken
+fn erode(pos: vec2<f32>, amount: f32) -> f32 {
+    let cx = i32(floor(pos.x));
+    let cz = i32(floor(pos.y));
+    let r = i32(params.radius);
+
+    var total = 0.0;
+    for (var dz = -r; dz <= r; dz++) {
+        for (var dx = -r; dx <= r; dx++) {
+            let x = cx + dx;
+            let z = cz + dz;
+            if x >= 0 && z >= 0 && x < i32(params.width) && z < i32(params.height) {
+                total += brush_weight(dx, dz);
+            }
+        }
+    }
+    if total <= 0.0 {
+        return 0.0;
+    }
+
+    for (var dz = -r; dz <= r; dz++) {
+        for (var dx = -r; dx <= r; dx++) {
+            let x = cx + dx;
+            let z = cz + dz;
+            let weight = brush_weight(dx, dz);
+            if weight > 0.0 && x >= 0 && z >= 0 && x < i32(params.width) && z < i32(params.height) {
+                atomicSub(&deltas[u32(z) * params.width + u32(x)], to_fixed(amount * weight / total));
+            }
+        }
+    }
+    return amount;
+}
+
+// large dispatches wrap into rows of workgroups to stay under the per
+// dimension limit, flatten them back into one index
+fn flat_index(id: vec3<u32>, groups: vec3<u32>) -> u32 {
+    return id.y * groups.x * 64u + id.x;
+}
+
+@compute @workgroup_size(64)
+fn erode_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let droplet = flat_index(id, groups);
+    if droplet >= params.droplets {
+        return;
+    }
+
+    var pos = starts[droplet];
+    var dir = vec2<f32>(0.0, 0.0);
+    var speed = params.initial_speed;
+    var water = params.initial_water;
+    var sediment = 0.0;
+    let max_x = f32(params.width - 1u);
+    let max_z = f32(params.height - 1u);
+
+    for (var step = 0u; step < params.max_lifetime; step++) {
+        let old_pos = pos;
+        let old = height_and_gradient(old_pos);
+
+        dir = dir * params.inertia - old.yz * (1.0 - params.inertia);
+        let len = sqrt(dir.x * dir.x + dir.y * dir.y);
+        if len <= 1.1920929e-7 {
+            break;
+        }
+        dir = dir / len;
+        pos = pos + dir;
+
+        // sediment carried off the edge of the map is lost
+        if pos.x < 0.0 || pos.y < 0.0 || pos.x >= max_x || pos.y >= max_z {
+            return;
+        }
+
+        let delta = height_and_gradient(pos).x - old.x;
+        let capacity = max(-delta, params.min_slope) * speed * water * params.capacity;
+
+        if sediment > capacity || delta > 0.0 {
+            var amount = (sediment - capacity) * params.deposition;
+            if delta > 0.0 {
+                amount = min(delta, sediment);
+            }
+            sediment -= amount;
+            deposit(old_pos, amount);
+        } else {
+            let amount = min((capacity - sediment) * params.erosion, -delta);
+            sediment += erode(old_pos, amount);
+        }
+
+        speed = sqrt(max(speed * speed - delta * params.gravity, 0.0));
+        water *= 1.0 - params.evaporation;
+    }
+
+    deposit(pos, sediment);
+}
+
+@compute @workgroup_size(64)
+fn apply_main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let i = flat_index(id, groups);
+    if i >= params.width * params.height {
+        return;
+    }
+
+    heights[i] += f32(atomicExchange(&deltas[i], 0)) / FIXED_SCALE;
+}
@@ -0,0 +1,359 @@
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::sim::terrain::Terrain;
+
+#[derive(Clone)]
+pub struct HydraulicErosionParams {
+    pub seed: u32,
+    pub droplets: u32,
+    pub max_lifetime: u32, // maximum number of steps a droplet takes before it is discarded
+    pub radius: usize,     // erosion brush radius in cells
+    pub inertia: f32,      // 0 follows the slope exactly, 1 never changes direction
+    pub capacity: f32,     // sediment capacity multiplier
+    pub min_slope: f32,    // lower bound on slope when computing capacity on flat ground
+    pub erosion: f32,      // fraction of free capacity eroded each step
+    pub deposition: f32,   // fraction of excess sediment deposited each step
+    pub evaporation: f32,  // fraction of water lost each step
+    pub gravity: f32,
+    pub initial_water: f32,
+    pub initial_speed: f32,
+}
+
+struct Droplet {
+    x: f32,
+    z: f32,
+    dir_x: f32,
+    dir_z: f32,
+    speed: f32,
+    water: f32,
+    sediment: Vec<(u16, f32)>, // carried material id and amount
+}
+
+impl Default for HydraulicErosionParams {
+    fn default() -> Self {
+        HydraulicErosionParams {
+            seed: 0,
+            droplets: 70_000,
+            max_lifetime: 30,
+            radius: 3,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            erosion: 0.3,
+            deposition: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            initial_water: 1.0,
+            initial_speed: 1.0,
+        }
+    }
+}
+
+impl Droplet {
+    fn new(x: f32, z: f32, params: &HydraulicErosionParams) -> Self {
+        Self {
+            x,
+            z,
+            dir_x: 0.0,
+            dir_z: 0.0,
+            speed: params.initial_speed,
+            water: params.initial_water,
+            sediment: Vec::new(),
+        }
+    }
+
+    fn sediment(&self) -> f32 {
+        self.sediment.iter().map(|s| s.1).sum()
+    }
+
+    fn pick_up(&mut self, thickness: f32, material_id: u16) {
+        match self.sediment.iter_mut().find(|s| s.0 == material_id) {
+            Some(s) => s.1 += thickness,
+            None => self.sediment.push((material_id, thickness)),
+        }
+    }
+
+    // removes `amount` of sediment, taken proportionally from every carried material
+    fn drop_off(&mut self, amount: f32) -> Vec<(u16, f32)> {
+        let total = self.sediment();
+        if total <= 0.0 || amount <= 0.0 {
+            return Vec::new();
+        }
+
+        let fraction = (amount / total).min(1.0);
+        let mut dropped = Vec::with_capacity(self.sediment.len());
+        for s in self.sediment.iter_mut() {
+            let thickness = s.1 * fraction;
+            s.1 -= thickness;
+            dropped.push((s.0, thickness));
+        }
+        self.sediment.retain(|s| s.1 > 0.0);
+
+        dropped
+    }
+}
+
+pub fn erode(terrain: &mut Terrain, params: &HydraulicErosionParams) {
+    if terrain.width < 2 || terrain.height < 2 {
+        return;
+    }
+
+    let mut rng = StdRng::seed_from_u64(params.seed as u64);
+    let mut heights = terrain.extract_heights();
+    let brush = brush(params.radius);
+
+    debug!(
+        "Simulating {} droplets over {}x{} terrain",
+        params.droplets, terrain.width, terrain.height
+    );
+
+    for _ in 0..params.droplets {
+        let droplet = Droplet::new(
+            rng.gen_range(0.0..(terrain.width - 1) as f32),
+            rng.gen_range(0.0..(terrain.height - 1) as f32),
+            params,
+        );
+        simulate(terrain, &mut heights, &brush, params, droplet);
+    }
+}
+
+fn simulate(
+    terrain: &mut Terrain,
+    heights: &mut [f32],
+    brush: &[(i32, i32, f32)],
+    params: &HydraulicErosionParams,
+    mut droplet: Droplet,
+) {
+    let width = terrain.width;
+    let height = terrain.height;
+
+    for _ in 0..params.max_lifetime {
+        let old_x = droplet.x;
+        let old_z = droplet.z;
+        let (old_height, grad_x, grad_z) = height_and_gradient(width, old_x, old_z, |i| heights[i]);
+
+        // ========== move ==========
+        droplet.dir_x = droplet.dir_x * params.inertia - grad_x * (1.0 - params.inertia);
+        droplet.dir_z = droplet.dir_z * params.inertia - grad_z * (1.0 - params.inertia);
+
+        let len = (droplet.dir_x * droplet.dir_x + droplet.dir_z * droplet.dir_z).sqrt();
+        if len <= f32::EPSILON {
+            break;
+        }
+        droplet.dir_x /= len;
+        droplet.dir_z /= len;
+        droplet.x += droplet.dir_x;
+        droplet.z += droplet.dir_z;
+
+        // sediment carried off the edge of the map is lost
+        if droplet.x < 0.0
+            || droplet.z < 0.0
+            || droplet.x >= (width - 1) as f32
+            || droplet.z >= (height - 1) as f32
+        {
+            return;
+        }
+
+        let (new_height, _, _) = height_and_gradient(width, droplet.x, droplet.z, |i| heights[i]);
+        let delta = new_height - old_height;
+
+        // ========== erode or deposit ==========
+        let carried = droplet.sediment();
+        let capacity =
+            (-delta).max(params.min_slope) * droplet.speed * droplet.water * params.capacity;
+
+        if carried > capacity || delta > 0.0 {
+            // fill the pit when moving uphill, otherwise drop a fraction of the excess
+            let amount = if delta > 0.0 {
+                delta.min(carried)
+            } else {
+                (carried - capacity) * params.deposition
+            };
+            deposit(terrain, heights, &mut droplet, old_x, old_z, amount);
+        } else {
+            let amount = ((capacity - carried) * params.erosion).min(-delta);
+            erode_brush(terrain, heights, brush, &mut droplet, old_x, old_z, amount);
+        }
+
+        droplet.speed = (droplet.speed * droplet.speed - delta * params.gravity)
+            .max(0.0)
+            .sqrt();
+        droplet.water *= 1.0 - params.evaporation;
+    }
+
+    // droplet has evaporated or come to rest, drop whatever it is still carrying
+    let carried = droplet.sediment();
+    let (x, z) = (droplet.x, droplet.z);
+    deposit(terrain, heights, &mut droplet, x, z, carried);
+}
+
+fn deposit(
+    terrain: &mut Terrain,
+    heights: &mut [f32],
+    droplet: &mut Droplet,
+    x: f32,
+    z: f32,
+    amount: f32,
+) {
+    let dropped = droplet.drop_off(amount);
+    if dropped.is_empty() {
+        return;
+    }
+
+    for (x, z, weight) in corners(x, z) {
+        if weight <= 0.0 {
+            continue;
+        }
+
+        let cell = terrain.cell_mut(x, z);
+        for &(material_id, thickness) in &dropped {
+            cell.deposit(thickness * weight, material_id);
+        }
+        heights[z * terrain.width + x] = cell.total_height();
+    }
+}
+
+fn erode_brush(
+    terrain: &mut Terrain,
+    heights: &mut [f32],
+    brush: &[(i32, i32, f32)],
+    droplet: &mut Droplet,
+    x: f32,
+    z: f32,
+    amount: f32,
+) {
+    if amount <= 0.0 {
+        return;
+    }
+
+    let (cells, total) = brush_cells(brush, terrain.width, terrain.height, x, z);
+    if total <= 0.0 {
+        return;
+    }
+
+    for (x, z, weight) in cells {
+        let cell = terrain.cell_mut(x, z);
+        for layer in cell.erode(amount * weight / total) {
+            droplet.pick_up(layer.thickness(), layer.material_id());
+        }
+        heights[z * terrain.width + x] = cell.total_height();
+    }
+}
+
+// brush cells around a position that fall inside the grid, with their total
+// weight so cells near the border still remove the full amount
+pub(crate) fn brush_cells(
+    brush: &[(i32, i32, f32)],
+    width: usize,
+    height: usize,
+    x: f32,
+    z: f32,
+) -> (Vec<(usize, usize, f32)>, f32) {
+    let cx = x.floor() as i32;
+    let cz = z.floor() as i32;
+    let cells: Vec<(usize, usize, f32)> = brush
+        .iter()
+        .map(|&(dx, dz, weight)| (cx + dx, cz + dz, weight))
+        .filter(|&(x, z, _)| x >= 0 && z >= 0 && x < width as i32 && z < height as i32)
+        .map(|(x, z, weight)| (x as usize, z as usize, weight))
+        .collect();
+    let total = cells.iter().map(|c| c.2).sum();
+
+    (cells, total)
+}
+
+// the four cells around a position inside the grid and their bilinear weights
+pub(crate) fn corners(x: f32, z: f32) -> [(usize, usize, f32); 4] {
+    let cx = x.floor() as usize;
+    let cz = z.floor() as usize;
+    let u = x - cx as f32;
+    let v = z - cz as f32;
+
+    [
+        (cx, cz, (1.0 - u) * (1.0 - v)),
+        (cx + 1, cz, u * (1.0 - v)),
+        (cx, cz + 1, (1.0 - u) * v),
+        (cx + 1, cz + 1, u * v),
+    ]
+}
+
+// bilinear height and gradient at a position inside the grid, reading the
+// height of each cell index through `height_at`
+pub(crate) fn height_and_gradient(
+    width: usize,
+    x: f32,
+    z: f32,
+    height_at: impl Fn(usize) -> f32,
+) -> (f32, f32, f32) {
+    let cx = x.floor() as usize;
+    let cz = z.floor() as usize;
+    let u = x - cx as f32;
+    let v = z - cz as f32;
+
+    let i = cz * width + cx;
+    let h00 = height_at(i);
+    let h10 = height_at(i + 1);
+    let h01 = height_at(i + width);
+    let h11 = height_at(i + width + 1);
+
+    let grad_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+    let grad_z = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+    let h = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+
+    (h, grad_x, grad_z)
+}
+
+// offsets and weights of the cells within `radius` of a droplet, weighted by distance
+pub(crate) fn brush(radius: usize) -> Vec<(i32, i32, f32)> {
+    if radius == 0 {
+        return vec![(0, 0, 1.0)];
+    }
+
+    let r = radius as i32;
+    let mut offsets = Vec::new();
+    for dz in -r..=r {
+        for dx in -r..=r {
+            let dist = ((dx * dx + dz * dz) as f32).sqrt();
+            if dist < radius as f32 {
+                offsets.push((dx, dz, 1.0 - dist / radius as f32));
+            }
+        }
+    }
+
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slope() -> Terrain {
+        let heights: Vec<f32> = (0..32 * 32)
+            .map(|i| 10.0 - (i % 32) as f32 * 0.2 + ((i / 32) as f32 * 0.7).sin())
+            .collect();
+        Terrain::from_heights(32, 32, &heights, 0).unwrap()
+    }
+
+    fn run(seed: u32) -> Vec<f32> {
+        let mut terrain = slope();
+        let params = HydraulicErosionParams {
+            seed,
+            droplets: 2_000,
+            ..Default::default()
+        };
+        erode(&mut terrain, &params);
+        terrain.extract_heights()
+    }
+
+    #[test]
+    fn same_seed_gives_same_terrain() {
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn different_seeds_give_different_terrain() {
+        assert_ne!(run(7), run(8));
+    }
+}
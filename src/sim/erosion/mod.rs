@@ -0,0 +1,4 @@
+pub mod hydraulic;
+pub mod shallow_water;
+pub mod thermal;
+pub mod wind;
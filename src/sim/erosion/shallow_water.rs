@@ -0,0 +1,494 @@
+use log::debug;
+
+use crate::sim::materials::MaterialRegistry;
+use crate::sim::terrain::Terrain;
+
+// pipes to the left, right, top and bottom neighbours
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+const TOP: usize = 2;
+const BOTTOM: usize = 3;
+const PIPES: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+// below this depth a cell counts as dry and its velocity is zeroed
+const DRY_DEPTH: f32 = 1e-4;
+
+// rain falling on a disc of cells
+#[derive(Clone)]
+pub struct RainSource {
+    pub x: f32,
+    pub z: f32,
+    pub radius: f32, // in cells
+    pub rate: f32,   // water depth added per second at the centre, fading to 0 at radius
+}
+
+// grid based shallow water erosion with virtual pipes between neighbouring cells
+// (Mei et al. 2007). Times are in seconds and lengths in height units
+#[derive(Clone)]
+pub struct ShallowWaterParams {
+    pub iterations: u32,
+    pub dt: f32,
+    pub cell_size: f32, // horizontal distance between cells, the length of each pipe
+    pub pipe_area: f32, // cross section of a pipe, scales how fast water moves
+    pub gravity: f32,
+    pub max_speed: f32, // cells per second, keeps thin fast sheets from stripping the ground
+    pub rain: f32,      // water depth added to every cell per second
+    pub sources: Vec<RainSource>,
+    pub evaporation: f32,  // fraction of water lost per second
+    pub capacity: f32,     // sediment capacity multiplier
+    pub min_tilt: f32,     // lower bound on sin(tilt) so flat water still carries sediment
+    pub full_depth: f32,   // water shallower than this carries proportionally less sediment
+    pub dissolving: f32,   // dissolving rate, scaled by the surface material's erosion
+    pub deposition: f32,   // fraction of excess sediment deposited per second
+    pub drain_edges: bool, // water flows off the map edge instead of banking up against it
+}
+
+impl Default for ShallowWaterParams {
+    fn default() -> Self {
+        ShallowWaterParams {
+            iterations: 500,
+            dt: 0.02,
+            cell_size: 1.0,
+            pipe_area: 1.0,
+            gravity: 9.81,
+            max_speed: 10.0,
+            rain: 0.0,
+            sources: Vec::new(),
+            evaporation: 0.05,
+            capacity: 1.0,
+            min_tilt: 0.05,
+            full_depth: 0.5,
+            dissolving: 0.5,
+            deposition: 1.0,
+            drain_edges: true,
+        }
+    }
+}
+
+// water and suspended sediment over a terrain, carried between calls to step
+pub struct ShallowWater {
+    width: usize,
+    height: usize,
+    water: Vec<f32>,
+    flux: Vec<[f32; 4]>,
+    velocity: Vec<[f32; 2]>,
+    // suspended thickness of each material, one grid per material id
+    sediment: Vec<(u16, Vec<f32>)>,
+}
+
+impl ShallowWater {
+    pub fn new(width: usize, height: usize) -> Self {
+        ShallowWater {
+            width,
+            height,
+            water: vec![0.0; width * height],
+            flux: vec![[0.0; 4]; width * height],
+            velocity: vec![[0.0; 2]; width * height],
+            sediment: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // water depth of every cell, row major like Terrain
+    pub fn water(&self) -> &[f32] {
+        &self.water
+    }
+
+    // (x, z) velocity in cells per second
+    pub fn velocity(&self, x: usize, z: usize) -> [f32; 2] {
+        self.velocity[z * self.width + x]
+    }
+
+    // suspended thickness of every material in each cell
+    pub fn sediment(&self) -> Vec<f32> {
+        let mut total = vec![0.0; self.water.len()];
+        for (_, grid) in &self.sediment {
+            for (t, s) in total.iter_mut().zip(grid) {
+                *t += s;
+            }
+        }
+        total
+    }
+
+    pub fn total_water(&self) -> f32 {
+        self.water.iter().sum()
+    }
+
+    pub fn step(
+        &mut self,
+        terrain: &mut Terrain,
+        materials: &MaterialRegistry,
+        params: &ShallowWaterParams,
+    ) -> anyhow::Result<()> {
+        assert!(
+            terrain.width == self.width && terrain.height == self.height,
+            "Water is {}x{} but terrain is {}x{}",
+            self.width,
+            self.height,
+            terrain.width,
+            terrain.height
+        );
+
+        materials.ensure_registered(terrain)?;
+
+        let mut heights = terrain.extract_heights();
+        self.add_water(params);
+        self.update_flux(&heights, params);
+        let old_water = self.update_water(params);
+        self.update_velocity(&old_water, params);
+        self.erode_and_deposit(terrain, &mut heights, materials, params);
+        self.transport(params);
+        self.evaporate(params);
+
+        Ok(())
+    }
+
+    // drops all suspended sediment onto the ground beneath it, as when the water
+    // dries up
+    pub fn settle(&mut self, terrain: &mut Terrain) {
+        for (material_id, grid) in self.sediment.drain(..) {
+            for (cell, thickness) in terrain.cells.iter_mut().zip(grid) {
+                if thickness > 0.0 {
+                    cell.deposit(thickness, material_id);
+                }
+            }
+        }
+    }
+
+    fn add_water(&mut self, params: &ShallowWaterParams) {
+        let dt = params.dt;
+        for (x, z, w) in cells_mut(&mut self.water, self.width) {
+            *w += params.rain * dt;
+            for source in &params.sources {
+                let dist = ((x as f32 - source.x).powi(2) + (z as f32 - source.z).powi(2)).sqrt();
+                if dist < source.radius {
+                    *w += source.rate * (1.0 - dist / source.radius) * dt;
+                }
+            }
+        }
+    }
+
+    // outflow through each pipe grows with the difference in water surface
+    // height, then is scaled back so no cell drains more water than it holds
+    fn update_flux(&mut self, heights: &[f32], params: &ShallowWaterParams) {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let l = params.cell_size;
+        let k = params.dt * params.pipe_area * params.gravity / l;
+
+        for z in 0..height {
+            for x in 0..width {
+                let i = (z * width + x) as usize;
+                let surface = heights[i] + self.water[i];
+                let mut total = 0.0;
+
+                for (pipe, (dx, dz)) in PIPES.iter().enumerate() {
+                    let (nx, nz) = (x + dx, z + dz);
+                    let outside = nx < 0 || nz < 0 || nx >= width || nz >= height;
+                    let neighbour = if !outside {
+                        let n = (nz * width + nx) as usize;
+                        heights[n] + self.water[n]
+                    } else if params.drain_edges {
+                        // the ground carries on level past the edge, but is dry
+                        heights[i]
+                    } else {
+                        self.flux[i][pipe] = 0.0;
+                        continue;
+                    };
+
+                    let f = (self.flux[i][pipe] + k * (surface - neighbour)).max(0.0);
+                    self.flux[i][pipe] = f;
+                    total += f;
+                }
+
+                if total > 0.0 {
+                    let scale = (self.water[i] * l * l / (total * params.dt)).min(1.0);
+                    for f in self.flux[i].iter_mut() {
+                        *f *= scale;
+                    }
+                }
+            }
+        }
+    }
+
+    // moves water along the pipes, returns the depths before the move
+    fn update_water(&mut self, params: &ShallowWaterParams) -> Vec<f32> {
+        let area = params.cell_size * params.cell_size;
+        let water = (0..self.water.len())
+            .map(|i| {
+                let outflow: f32 = self.flux[i].iter().sum();
+                let inflow: f32 = self.inflow(i % self.width, i / self.width).iter().sum();
+                (self.water[i] + params.dt * (inflow - outflow) / area).max(0.0)
+            })
+            .collect();
+
+        std::mem::replace(&mut self.water, water)
+    }
+
+    // flux into (x, z) from its left, right, top and bottom neighbours
+    fn inflow(&self, x: usize, z: usize) -> [f32; 4] {
+        let from = |dx: i32, dz: i32, pipe: usize| {
+            let (nx, nz) = (x as i32 + dx, z as i32 + dz);
+            if nx < 0 || nz < 0 || nx >= self.width as i32 || nz >= self.height as i32 {
+                0.0
+            } else {
+                self.flux[nz as usize * self.width + nx as usize][pipe]
+            }
+        };
+
+        [
+            from(-1, 0, RIGHT),
+            from(1, 0, LEFT),
+            from(0, -1, BOTTOM),
+            from(0, 1, TOP),
+        ]
+    }
+
+    fn update_velocity(&mut self, old_water: &[f32], params: &ShallowWaterParams) {
+        for z in 0..self.height {
+            for x in 0..self.width {
+                let i = z * self.width + x;
+                let depth = (old_water[i] + self.water[i]) * 0.5;
+                if depth < DRY_DEPTH {
+                    self.velocity[i] = [0.0; 2];
+                    continue;
+                }
+
+                let inflow = self.inflow(x, z);
+                let f = self.flux[i];
+                let through_x = (inflow[LEFT] - f[LEFT] + f[RIGHT] - inflow[RIGHT]) * 0.5;
+                let through_z = (inflow[TOP] - f[TOP] + f[BOTTOM] - inflow[BOTTOM]) * 0.5;
+                // water per second through a cell wide section, in cells per second
+                let l = params.cell_size;
+                let [u, v] = [through_x / (depth * l) / l, through_z / (depth * l) / l];
+                let speed = (u * u + v * v).sqrt();
+                let scale = if speed > params.max_speed {
+                    params.max_speed / speed
+                } else {
+                    1.0
+                };
+                self.velocity[i] = [u * scale, v * scale];
+            }
+        }
+    }
+
+    // fast water over steep ground dissolves the surface, slow water drops what
+    // it carries beyond its capacity
+    fn erode_and_deposit(
+        &mut self,
+        terrain: &mut Terrain,
+        heights: &mut [f32],
+        materials: &MaterialRegistry,
+        params: &ShallowWaterParams,
+    ) {
+        let width = self.width;
+        let tilts: Vec<f32> = (0..heights.len())
+            .map(|i| sin_tilt(heights, width, self.height, i, params.cell_size))
+            .collect();
+
+        for (i, cell) in terrain.cells.iter_mut().enumerate() {
+            let [u, v] = self.velocity[i];
+            let speed = (u * u + v * v).sqrt() * params.cell_size;
+            // thin sheets of water move fast but cannot carry much
+            let depth = (self.water[i] / params.full_depth.max(f32::EPSILON)).min(1.0);
+            let capacity = params.capacity * tilts[i].max(params.min_tilt) * speed * depth;
+            let carried: f32 = self.sediment.iter().map(|(_, grid)| grid[i]).sum();
+
+            if capacity > carried {
+                let Some(surface) = cell.surface_material() else {
+                    continue;
+                };
+                let erosion = materials.get(surface).erosion;
+                let amount = params.dissolving * erosion * (capacity - carried) * params.dt;
+                for layer in cell.erode(amount) {
+                    let grid = self.sediment_grid(layer.material_id());
+                    grid[i] += layer.thickness();
+                }
+            } else if carried > 0.0 {
+                let fraction =
+                    (params.deposition * params.dt).min(1.0) * (carried - capacity) / carried;
+                for (material_id, grid) in self.sediment.iter_mut() {
+                    let thickness = grid[i] * fraction;
+                    grid[i] -= thickness;
+                    if thickness > 0.0 {
+                        cell.deposit(thickness, *material_id);
+                    }
+                }
+            }
+            heights[i] = cell.total_height();
+        }
+    }
+
+    fn sediment_grid(&mut self, material_id: u16) -> &mut Vec<f32> {
+        let slot = match self.sediment.iter().position(|(id, _)| *id == material_id) {
+            Some(slot) => slot,
+            None => {
+                self.sediment
+                    .push((material_id, vec![0.0; self.water.len()]));
+                self.sediment.len() - 1
+            }
+        };
+        &mut self.sediment[slot].1
+    }
+
+    // semi-lagrangian advection, each cell takes the sediment found where its water
+    // was one step ago. Draining edges bring in clean water from beyond the map,
+    // closed edges read the edge cells and keep the total suspended amount
+    fn transport(&mut self, params: &ShallowWaterParams) {
+        let (width, height) = (self.width, self.height);
+        let max_x = width.saturating_sub(1) as f32;
+        let max_z = height.saturating_sub(1) as f32;
+        for (_, grid) in self.sediment.iter_mut() {
+            let old = grid.clone();
+            for (i, s) in grid.iter_mut().enumerate() {
+                let [u, v] = self.velocity[i];
+                let mut x = (i % width) as f32 - u * params.dt;
+                let mut z = (i / width) as f32 - v * params.dt;
+                if !params.drain_edges {
+                    x = x.clamp(0.0, max_x);
+                    z = z.clamp(0.0, max_z);
+                }
+                *s = sample(&old, width, height, x, z);
+            }
+
+            // bilinear resampling gains or loses a little where the flow
+            // converges or spreads, nothing can leave through a closed edge
+            if !params.drain_edges {
+                let before: f32 = old.iter().sum();
+                let after: f32 = grid.iter().sum();
+                if after > 0.0 {
+                    let scale = before / after;
+                    for s in grid.iter_mut() {
+                        *s *= scale;
+                    }
+                }
+            }
+        }
+    }
+
+    fn evaporate(&mut self, params: &ShallowWaterParams) {
+        let keep = (1.0 - params.evaporation * params.dt).max(0.0);
+        for w in self.water.iter_mut() {
+            *w *= keep;
+        }
+    }
+}
+
+// runs the simulation from dry ground and settles the sediment still suspended
+// at the end, returning the water left over
+pub fn erode(
+    terrain: &mut Terrain,
+    materials: &MaterialRegistry,
+    params: &ShallowWaterParams,
+) -> anyhow::Result<ShallowWater> {
+    let mut water = ShallowWater::new(terrain.width, terrain.height);
+    if terrain.width == 0 || terrain.height == 0 {
+        return Ok(water);
+    }
+
+    debug!(
+        "Running {} shallow water steps over {}x{} terrain",
+        params.iterations, terrain.width, terrain.height
+    );
+
+    for _ in 0..params.iterations {
+        water.step(terrain, materials, params)?;
+    }
+    water.settle(terrain);
+
+    Ok(water)
+}
+
+fn cells_mut(grid: &mut [f32], width: usize) -> impl Iterator<Item = (usize, usize, &mut f32)> {
+    grid.iter_mut()
+        .enumerate()
+        .map(move |(i, v)| (i % width, i / width, v))
+}
+
+// sine of the ground's slope angle from central differences
+fn sin_tilt(heights: &[f32], width: usize, height: usize, i: usize, cell_size: f32) -> f32 {
+    let (x, z) = (i % width, i / width);
+    let h = |x: usize, z: usize| heights[z * width + x];
+
+    let (l, r) = (x.saturating_sub(1), (x + 1).min(width - 1));
+    let (t, b) = (z.saturating_sub(1), (z + 1).min(height - 1));
+    let dx = (h(r, z) - h(l, z)) / ((r - l).max(1) as f32 * cell_size);
+    let dz = (h(x, b) - h(x, t)) / ((b - t).max(1) as f32 * cell_size);
+
+    let gradient = (dx * dx + dz * dz).sqrt();
+    gradient / (1.0 + gradient * gradient).sqrt()
+}
+
+// bilinear sample, 0 outside the grid
+fn sample(grid: &[f32], width: usize, height: usize, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let (u, v) = (x - x0, z - z0);
+    let at = |x: f32, z: f32| {
+        if x < 0.0 || z < 0.0 || x >= width as f32 || z >= height as f32 {
+            0.0
+        } else {
+            grid[z as usize * width + x as usize]
+        }
+    };
+
+    at(x0, z0) * (1.0 - u) * (1.0 - v)
+        + at(x0 + 1.0, z0) * u * (1.0 - v)
+        + at(x0, z0 + 1.0) * (1.0 - u) * v
+        + at(x0 + 1.0, z0 + 1.0) * u * v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::materials::MaterialProperties;
+
+    fn slope() -> Terrain {
+        let heights: Vec<f32> = (0..24 * 24)
+            .map(|i| 4.0 + (i % 24) as f32 * 0.1 + ((i / 24) as f32 * 0.5).sin())
+            .collect();
+        Terrain::from_heights(24, 24, &heights, 0).unwrap()
+    }
+
+    fn total(terrain: &Terrain) -> f32 {
+        terrain.extract_heights().iter().sum()
+    }
+
+    #[test]
+    fn closed_edges_conserve_material() {
+        let mut materials = MaterialRegistry::new();
+        materials.register(
+            "soil",
+            MaterialProperties {
+                erosion: 1.0,
+                ..Default::default()
+            },
+        );
+        let params = ShallowWaterParams {
+            iterations: 200,
+            rain: 0.5,
+            drain_edges: false,
+            ..Default::default()
+        };
+
+        let mut terrain = slope();
+        let before = total(&terrain);
+        let water = erode(&mut terrain, &materials, &params).unwrap();
+
+        assert!(water.sediment().iter().all(|&s| s == 0.0));
+        assert_ne!(terrain.extract_heights(), slope().extract_heights());
+        assert!((total(&terrain) - before).abs() < before * 1e-4);
+    }
+
+    #[test]
+    fn unregistered_material_is_an_error() {
+        let mut terrain = slope();
+        let materials = MaterialRegistry::new();
+        assert!(erode(&mut terrain, &materials, &ShallowWaterParams::default()).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use log::debug;
+
+use crate::sim::grid::NEIGHBOURS;
+use crate::sim::materials::{MaterialProperties, MaterialRegistry};
+use crate::sim::terrain::Terrain;
+
+#[derive(Clone)]
+pub struct ThermalErosionParams {
+    pub iterations: u32,
+    pub rate: f32,      // fraction of the excess slope moved each iteration, 0..1
+    pub cell_size: f32, // horizontal distance between cells in height units
+    pub min_angle: f32, // talus angle in degrees of a material with no cohesion
+    pub max_angle: f32, // talus angle in degrees of a fully cohesive material
+}
+
+struct Slide {
+    from: usize,
+    amount: f32,
+    targets: Vec<(usize, f32)>, // neighbour index and share of the amount
+}
+
+impl Default for ThermalErosionParams {
+    fn default() -> Self {
+        ThermalErosionParams {
+            iterations: 50,
+            rate: 0.5,
+            cell_size: 1.0,
+            min_angle: 30.0,
+            max_angle: 60.0,
+        }
+    }
+}
+
+impl ThermalErosionParams {
+    // maximum stable height difference between neighbouring cells one cell apart
+    pub fn talus(&self, material: &MaterialProperties) -> f32 {
+        let cohesion = material.cohesion.clamp(0.0, 1.0);
+        let angle = self.min_angle + (self.max_angle - self.min_angle) * cohesion;
+        angle.to_radians().tan() * self.cell_size
+    }
+}
+
+pub fn erode(
+    terrain: &mut Terrain,
+    materials: &MaterialRegistry,
+    params: &ThermalErosionParams,
+) -> anyhow::Result<()> {
+    materials.ensure_registered(terrain)?;
+    debug!(
+        "Running {} thermal iterations over {}x{} terrain",
+        params.iterations, terrain.width, terrain.height
+    );
+
+    for _ in 0..params.iterations {
+        let slides = find_slides(terrain, materials, params);
+        if slides.is_empty() {
+            break;
+        }
+        apply_slides(terrain, slides);
+    }
+
+    Ok(())
+}
+
+// works from a snapshot of the heights so the result does not depend on cell order
+fn find_slides(
+    terrain: &Terrain,
+    materials: &MaterialRegistry,
+    params: &ThermalErosionParams,
+) -> Vec<Slide> {
+    let width = terrain.width as i32;
+    let height = terrain.height as i32;
+    let heights = terrain.extract_heights();
+    let mut slides = Vec::new();
+
+    for z in 0..height {
+        for x in 0..width {
+            let cell = terrain.cell(x as usize, z as usize);
+            let Some(surface) = cell.surface_layer() else {
+                continue;
+            };
+
+            let i = (z * width + x) as usize;
+            let talus = params.talus(materials.get(surface.material_id()));
+            let mut targets = Vec::with_capacity(NEIGHBOURS.len());
+            let mut total_excess = 0.0;
+            let mut max_excess: f32 = 0.0;
+
+            for (dx, dz) in NEIGHBOURS {
+                let nx = x + dx;
+                let nz = z + dz;
+                if nx < 0 || nz < 0 || nx >= width || nz >= height {
+                    continue;
+                }
+
+                let n = (nz * width + nx) as usize;
+                let distance = ((dx * dx + dz * dz) as f32).sqrt();
+                let excess = heights[i] - heights[n] - talus * distance;
+                if excess > 0.0 {
+                    targets.push((n, excess));
+                    total_excess += excess;
+                    max_excess = max_excess.max(excess);
+                }
+            }
+
+            if targets.is_empty() {
+                continue;
+            }
+
+            // only the exposed surface layer is loose enough to slide
+            let amount = (params.rate * max_excess * 0.5).min(surface.thickness());
+            for t in targets.iter_mut() {
+                t.1 /= total_excess;
+            }
+
+            slides.push(Slide {
+                from: i,
+                amount,
+                targets,
+            });
+        }
+    }
+
+    slides
+}
+
+fn apply_slides(terrain: &mut Terrain, slides: Vec<Slide>) {
+    // erode every source before depositing so each slide moves the material it saw
+    let removed: Vec<_> = slides
+        .iter()
+        .map(|s| terrain.cells[s.from].erode(s.amount))
+        .collect();
+
+    for (slide, layers) in slides.iter().zip(removed) {
+        for &(n, share) in &slide.targets {
+            for layer in &layers {
+                terrain.cells[n].deposit(layer.thickness() * share, layer.material_id());
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::thermal::{self, ThermalErosionParams};
+use crate::sim::grid::NEIGHBOURS;
+use crate::sim::materials::MaterialRegistry;
+use crate::sim::terrain::Terrain;
+
+#[derive(Clone)]
+pub struct WindErosionParams {
+    pub seed: u32,
+    pub iterations: u32, // number of sweeps, each lifts on average one slab per cell
+    pub direction: f32,  // prevailing wind direction in degrees, 0 blows towards +x
+    pub strength: f32,   // wind speed multiplier
+    pub turbulence: f32, // random deviation from the prevailing direction in degrees
+    pub slab: f32,       // thickness of material lifted at once
+    pub threshold: f32,  // materials with mass below strength * threshold can be lifted
+    pub hop_length: f32, // saltation distance in cells for a massless grain at strength 1
+    // degrees, cells behind a steeper slope are sheltered
+    pub shadow_angle: f32,
+    // how far upwind to look for sheltering terrain
+    pub shadow_distance: u32,
+    pub p_sand: f32,    // chance of settling on the same material
+    pub p_bare: f32,    // chance of settling on any other surface
+    pub reptation: f32, // fraction of a landing slab that creeps to the lowest neighbour
+    pub max_hops: u32,
+    pub wrap: bool, // material leaving one edge re-enters at the opposite edge
+    pub avalanche: ThermalErosionParams,
+}
+
+impl Default for WindErosionParams {
+    fn default() -> Self {
+        WindErosionParams {
+            seed: 0,
+            iterations: 20,
+            direction: 0.0,
+            strength: 1.0,
+            turbulence: 10.0,
+            slab: 0.1,
+            threshold: 1.0,
+            hop_length: 5.0,
+            shadow_angle: 15.0,
+            shadow_distance: 20,
+            p_sand: 0.6,
+            p_bare: 0.4,
+            reptation: 0.2,
+            max_hops: 50,
+            wrap: false,
+            avalanche: ThermalErosionParams {
+                iterations: 1,
+                min_angle: 33.0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+pub fn erode(
+    terrain: &mut Terrain,
+    materials: &MaterialRegistry,
+    params: &WindErosionParams,
+) -> anyhow::Result<()> {
+    materials.ensure_registered(terrain)?;
+    if terrain.width == 0 || terrain.height == 0 {
+        return Ok(());
+    }
+
+    let mut rng = StdRng::seed_from_u64(params.seed as u64);
+    let cells = terrain.width * terrain.height;
+
+    debug!(
+        "Running {} wind iterations over {}x{} terrain",
+        params.iterations, terrain.width, terrain.height
+    );
+
+    for _ in 0..params.iterations {
+        let mut shadow = shadow_map(terrain, params);
+
+        for _ in 0..cells {
+            let x = rng.gen_range(0..terrain.width);
+            let z = rng.gen_range(0..terrain.height);
+            if shadow[z * terrain.width + x] {
+                continue;
+            }
+
+            let Some(surface) = terrain.cell(x, z).surface_layer().copied() else {
+                continue;
+            };
+            let material = materials.get(surface.material_id());
+            if material.mass >= params.strength * params.threshold {
+                continue;
+            }
+
+            let lifted = terrain
+                .cell_mut(x, z)
+                .erode(params.slab.min(surface.thickness()));
+            let Some(slab) = lifted.first() else {
+                continue;
+            };
+
+            let hop = (params.hop_length * params.strength / (1.0 + material.mass)).max(1.0);
+            if let Some((lx, lz)) = saltate(
+                terrain,
+                &shadow,
+                params,
+                &mut rng,
+                (x, z),
+                hop,
+                slab.material_id(),
+            ) {
+                settle(
+                    terrain,
+                    params,
+                    lx,
+                    lz,
+                    slab.thickness(),
+                    slab.material_id(),
+                );
+                shadow[lz * terrain.width + lx] |= is_shadowed(terrain, params, lx, lz);
+            }
+        }
+
+        thermal::erode(terrain, materials, &params.avalanche)?;
+    }
+
+    Ok(())
+}
+
+// hops the slab downwind until it settles, returns None if it leaves the map
+fn saltate(
+    terrain: &Terrain,
+    shadow: &[bool],
+    params: &WindErosionParams,
+    rng: &mut StdRng,
+    from: (usize, usize),
+    hop: f32,
+    material_id: u16,
+) -> Option<(usize, usize)> {
+    let (mut fx, mut fz) = (from.0 as f32 + 0.5, from.1 as f32 + 0.5);
+
+    for _ in 0..params.max_hops {
+        let angle = if params.turbulence > 0.0 {
+            params.direction + rng.gen_range(-params.turbulence..params.turbulence)
+        } else {
+            params.direction
+        };
+        let angle = angle.to_radians();
+        fx += angle.cos() * hop;
+        fz += angle.sin() * hop;
+
+        let (lx, lz) = wrap_position(terrain, params, fx, fz)?;
+        fx = lx as f32 + fx.rem_euclid(1.0);
+        fz = lz as f32 + fz.rem_euclid(1.0);
+
+        if shadow[lz * terrain.width + lx] {
+            return Some((lx, lz));
+        }
+
+        let p = match terrain.cell(lx, lz).surface_material() {
+            Some(id) if id == material_id => params.p_sand,
+            _ => params.p_bare,
+        };
+        if rng.r#gen::<f32>() < p {
+            return Some((lx, lz));
+        }
+    }
+
+    wrap_position(terrain, params, fx, fz)
+}
+
+fn settle(
+    terrain: &mut Terrain,
+    params: &WindErosionParams,
+    x: usize,
+    z: usize,
+    thickness: f32,
+    material_id: u16,
+) {
+    let creep = thickness * params.reptation.clamp(0.0, 1.0);
+    let here = terrain.cell(x, z).total_height() + thickness - creep;
+
+    // reptation, grains knocked loose on impact roll to the lowest neighbour
+    let lowest = NEIGHBOURS
+        .iter()
+        .filter_map(|&(dx, dz)| {
+            let nx = x as i32 + dx;
+            let nz = z as i32 + dz;
+            if nx < 0 || nz < 0 || nx >= terrain.width as i32 || nz >= terrain.height as i32 {
+                return None;
+            }
+            let (nx, nz) = (nx as usize, nz as usize);
+            Some((nx, nz, terrain.cell(nx, nz).total_height()))
+        })
+        .filter(|n| n.2 < here)
+        .min_by(|a, b| a.2.total_cmp(&b.2));
+
+    match lowest {
+        Some((nx, nz, _)) if creep > 0.0 => {
+            terrain
+                .cell_mut(x, z)
+                .deposit(thickness - creep, material_id);
+            terrain.cell_mut(nx, nz).deposit(creep, material_id);
+        }
+        _ => terrain.cell_mut(x, z).deposit(thickness, material_id),
+    }
+}
+
+fn wrap_position(
+    terrain: &Terrain,
+    params: &WindErosionParams,
+    x: f32,
+    z: f32,
+) -> Option<(usize, usize)> {
+    let width = terrain.width as i32;
+    let height = terrain.height as i32;
+    let (cx, cz) = (x.floor() as i32, z.floor() as i32);
+
+    if params.wrap {
+        return Some((
+            cx.rem_euclid(width) as usize,
+            cz.rem_euclid(height) as usize,
+        ));
+    }
+    if cx < 0 || cz < 0 || cx >= width || cz >= height {
+        return None;
+    }
+
+    Some((cx as usize, cz as usize))
+}
+
+fn shadow_map(terrain: &Terrain, params: &WindErosionParams) -> Vec<bool> {
+    let mut shadow = vec![false; terrain.width * terrain.height];
+    for z in 0..terrain.height {
+        for x in 0..terrain.width {
+            shadow[z * terrain.width + x] = is_shadowed(terrain, params, x, z);
+        }
+    }
+
+    shadow
+}
+
+// a cell is sheltered when terrain upwind rises above the shadow angle
+fn is_shadowed(terrain: &Terrain, params: &WindErosionParams, x: usize, z: usize) -> bool {
+    let h = terrain.cell(x, z).total_height();
+    let tan = params.shadow_angle.to_radians().tan();
+    let angle = params.direction.to_radians();
+    let (dir_x, dir_z) = (angle.cos(), angle.sin());
+
+    for step in 1..=params.shadow_distance {
+        let d = step as f32;
+        let ux = x as f32 + 0.5 - dir_x * d;
+        let uz = z as f32 + 0.5 - dir_z * d;
+        let Some((ux, uz)) = wrap_position(terrain, params, ux, uz) else {
+            break;
+        };
+
+        if terrain.cell(ux, uz).total_height() - h > tan * d {
+            return true;
+        }
+    }
+
+    false
+}
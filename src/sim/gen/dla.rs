@@ -1,12 +1,24 @@
-use image::{GrayImage, Luma, Rgb, RgbImage};
+use anyhow::Context;
+use image::{GrayImage, Rgb, RgbImage};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{Level, debug, error, info, log_enabled};
-use rand::Rng;
-use std::collections::{HashMap, HashSet};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::f32::consts::PI;
 use std::ops::{Mul, Sub};
+use std::path::{Path, PathBuf};
 
-#[derive(Clone)]
+use super::filter::{BorderMode, Filter};
+use super::io::HeightMapFormat;
+use super::lib::HeightMap;
+use grid::ParticleGrid;
+
+mod grid;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DiffusionLimitedAggregationParams {
     pub height: usize, // starting width
     pub width: usize,  // starting height
@@ -16,36 +28,46 @@ pub struct DiffusionLimitedAggregationParams {
     pub layers: u32, // number of layer scalings, each layer scales width/height by factor of 2
     pub density: f32,
     pub kernel: Kernel,
+    pub output_dir: Option<PathBuf>, // when set, intermediate layer images are written here
+    pub seed: u32,
+    pub spawn_pattern: ParticleSpawnPattern,
+    #[serde(skip)]
+    pub mode: DiffusionLimitedAggregationMode,
+    pub walkers: usize, // particles walked concurrently, 1 matches sequential aggregation
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Kernel {
     pub size: usize,
     pub value: f32,
     pub k_type: KernelType,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum KernelType {
-    Gaussian,
-    SingleValue,
-    Directional,
+    Gaussian,    // value is the standard deviation
+    SingleValue, // every weight is value
+    Directional, // blur along the angle in degrees given by value
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Default)]
 pub enum DiffusionLimitedAggregationMode {
-    Particle,
-    Image,
+    #[default]
+    Particle, // aggregate from the spawn points only
+    Image(GrayImage), // bright pixels of the mask seed the aggregate, resized to the base layer
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ParticleSpawnPattern {
     Random,
     Edge,
-    Radius, // radius - spawn particles at a weighted value of the mean radius of the current particles
-    Pattern, // pattern - need to define some patterns for spawn gen
+    Radius(f32), // radius - spawn particles at a weighted value of the mean radius of the current particles
+    Pattern(Vec<Point>), // pattern - spawn along a polyline given in base layer coordinates
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Point {
     pub x: u32,
     pub y: u32,
@@ -57,6 +79,108 @@ pub struct Particle {
     pub point: Point,
 }
 
+impl Default for DiffusionLimitedAggregationParams {
+    fn default() -> Self {
+        DiffusionLimitedAggregationParams {
+            height: 64,
+            width: 64,
+            spawns: vec![Point::new(32, 32)],
+            t: 1.0,
+            particles: 1000,
+            layers: 4,
+            density: 0.08,
+            kernel: Kernel {
+                size: 25,
+                value: 2.0,
+                k_type: KernelType::Gaussian,
+            },
+            output_dir: None,
+            seed: 0,
+            spawn_pattern: ParticleSpawnPattern::Random,
+            mode: DiffusionLimitedAggregationMode::Particle,
+            walkers: 256,
+        }
+    }
+}
+
+impl DiffusionLimitedAggregationParams {
+    // width and height of the heightmap returned by generate
+    pub fn output_size(&self) -> (usize, usize) {
+        (self.width << self.layers, self.height << self.layers)
+    }
+
+    fn output_path(&self, name: &str) -> Option<PathBuf> {
+        self.output_dir.as_ref().map(|dir| dir.join(name))
+    }
+}
+
+impl DiffusionLimitedAggregationMode {
+    pub fn from_image(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mask = image::open(path)
+            .with_context(|| format!("Failed to open DLA mask {}", path.display()))?
+            .to_luma8();
+        Ok(DiffusionLimitedAggregationMode::Image(mask))
+    }
+}
+
+// running centre and spread of the aggregate, used by the radius spawn pattern
+// sums are kept as integers so they do not depend on the map iteration order
+struct Cluster {
+    count: u64,
+    sum_x: u64,
+    sum_y: u64,
+    sum_sq: u64,
+}
+
+impl Cluster {
+    fn from_points(points: impl Iterator<Item = Point>) -> Self {
+        let mut cluster = Cluster {
+            count: 0,
+            sum_x: 0,
+            sum_y: 0,
+            sum_sq: 0,
+        };
+        for p in points {
+            cluster.add(p);
+        }
+
+        cluster
+    }
+
+    fn add(&mut self, p: Point) {
+        let (x, y) = (p.x as u64, p.y as u64);
+        self.count += 1;
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_sq += x * x + y * y;
+    }
+
+    fn centre(&self) -> (f32, f32) {
+        if self.count == 0 {
+            return (0.0, 0.0);
+        }
+        let count = self.count as f64;
+        (
+            (self.sum_x as f64 / count) as f32,
+            (self.sum_y as f64 / count) as f32,
+        )
+    }
+
+    // root mean square distance of the particles from the centre
+    fn radius(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let count = self.count as f64;
+        let cx = self.sum_x as f64 / count;
+        let cy = self.sum_y as f64 / count;
+        (self.sum_sq as f64 / count - cx * cx - cy * cy)
+            .max(0.0)
+            .sqrt() as f32
+    }
+}
+
 impl Point {
     pub fn new(x: u32, y: u32) -> Self {
         Self { x, y }
@@ -141,79 +265,236 @@ impl Particle {
 }
 
 impl Kernel {
-    fn to_vec(&self) -> Vec<Vec<f32>> {
-        assert!(self.size % 2 == 1, "Kernel size must be odd");
+    pub fn to_filter(&self) -> anyhow::Result<Filter> {
+        anyhow::ensure!(
+            self.size % 2 == 1,
+            "Kernel size must be odd, got {}",
+            self.size
+        );
+        let radius = self.size / 2;
+
+        Ok(match self.k_type {
+            KernelType::SingleValue => Filter::Separable {
+                column: vec![1.0; self.size],
+                row: vec![self.value; self.size],
+            },
+            KernelType::Gaussian => Filter::Gaussian {
+                sigma: self.value,
+                radius,
+            },
+            KernelType::Directional => Filter::Anisotropic {
+                sigma_along: (radius as f32 / 2.0).max(0.5),
+                sigma_across: 0.5,
+                angle: self.value,
+                radius,
+            },
+        })
+    }
+}
+
+pub fn generate(params: DiffusionLimitedAggregationParams) -> HeightMap {
+    let height_scale = 100.0;
+
+    let mut height_map = HeightMap::filled(params.width, params.height, 1.0);
+    let mut rng = StdRng::seed_from_u64(params.seed as u64);
+
+    if let Some(dir) = &params.output_dir
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        error!(
+            "Failed to create DLA output directory {}: {}",
+            dir.display(),
+            e
+        );
+    }
+
+    let mut point_map: HashMap<(u32, u32), Particle> = HashMap::new();
+    if let DiffusionLimitedAggregationMode::Image(mask) = &params.mode {
+        debug!("Adding mask particles to DLA image");
+        point_map = mask_particles(mask, params.width, params.height);
+    }
 
-        match self.k_type {
-            KernelType::SingleValue => {
-                vec![vec![self.value; self.size]; self.size]
+    debug!("Adding starting points to DLA image");
+    for p in params.spawns.clone() {
+        point_map.entry(p.key()).or_insert_with(|| Particle::new(p));
+    }
+    let mut grid = ParticleGrid::from_map(&point_map, params.width, params.height);
+
+    for layer in 0..params.layers.max(1) {
+        if layer > 0 {
+            // ========== scale heightmap ==========
+            debug!("Scaling heightmap layer {}", layer);
+            height_map = scale_heightmap(&height_map);
+            let mut layer_kernel = params.kernel;
+            layer_kernel.size =
+                (params.kernel.size as f32 * 2_u32.pow(layer) as f32 / 12.0) as usize;
+            if layer_kernel.size.is_multiple_of(2) {
+                layer_kernel.size += 1;
+            }
+            height_map = filter_heightmap(&height_map, &layer_kernel);
+            if let Some(path) = params.output_path(&format!("layer_{}_heightmap_base.png", layer)) {
+                save_heightmap_as_png(&height_map, &path);
             }
 
-            KernelType::Gaussian => {
-                let mut kernel = vec![vec![0.0; self.size]; self.size];
-                let mut sum = 0.0;
-                let center = (self.size / 2) as isize;
-
-                for y in 0..self.size {
-                    for x in 0..self.size {
-                        let dx = x as isize - center;
-                        let dy = y as isize - center;
-                        let exponent =
-                            -((dx * dx + dy * dy) as f32) / (2.0 * self.value * self.value);
-                        let value =
-                            (1.0 / (2.0 * PI * self.value * self.value)) * f32::exp(exponent);
-                        kernel[y][x] = value;
-                        sum += value;
-                    }
-                }
+            // ========== scale particle map ==========
+            debug!("Scaling particle map");
+            grid = grid.scale();
+        }
 
-                for row in &mut kernel {
-                    for v in row.iter_mut() {
-                        *v /= sum;
-                    }
-                }
+        // ========== add to particle map ==========
+        let density = if layer == 0 { 0.08 } else { 0.1 };
+        let layer_params = DiffusionLimitedAggregationParams {
+            height: grid.height,
+            width: grid.width,
+            particles: (density * grid.width as f32 * grid.height as f32) as u32,
+            ..params.clone()
+        };
+
+        debug!(
+            "populating layer {}/{} with dimensions {}x{}",
+            layer + 1,
+            params.layers,
+            layer_params.width,
+            layer_params.height
+        );
+        populate_grid(&mut grid, &layer_params, 2_u32.pow(layer), &mut rng);
 
-                kernel
+        // ========== add particle map to heightmap ==========
+        debug!("Adding to heightmap");
+        let depths = grid.depths();
+        for (h, &depth) in height_map.iter_mut().zip(&depths) {
+            if depth > 0 {
+                *h += height_scale * gradient_growth_limited(depth as f32);
             }
+        }
 
-            KernelType::Directional => {
-                let mut kernel = vec![vec![0.0; self.size]; self.size];
-                let center = (self.size / 2) as isize;
-
-                let angle_rad = self.value.to_radians();
-                let dir_x = angle_rad.cos();
-                let dir_y = angle_rad.sin();
-
-                for y in 0..self.size {
-                    for x in 0..self.size {
-                        let dx = x as isize - center;
-                        let dy = y as isize - center;
-
-                        let proj = dx as f32 * dir_x + dy as f32 * dir_y;
-
-                        if proj.abs() < 1e-6 {
-                            kernel[y][x] = 0.0;
-                        } else if proj < 0.0 {
-                            kernel[y][x] = -1.0;
-                        } else {
-                            kernel[y][x] = 1.0;
-                        }
-                    }
-                }
+        // ========== save images ==========
+        if let Some(path) = params.output_path(&format!("layer_{}_particle.png", layer)) {
+            debug!("Saving layer image");
+            save_particle_map_as_png(grid.points(), &layer_params, &path);
+        }
+
+        if let Some(path) = params.output_path(&format!("layer_{}_heightmap_detailed.png", layer)) {
+            save_heightmap_as_png(&height_map, &path);
+        }
+    }
+
+    debug!("saving final heightmap");
+    if params.layers > 0 {
+        height_map = scale_heightmap(&height_map);
+    }
+    let mut layer_kernel = params.kernel;
+    layer_kernel.size = (params.height as f32 * 2_u32.pow(params.layers) as f32 / 30.0) as usize;
+    if layer_kernel.size.is_multiple_of(2) {
+        layer_kernel.size += 1;
+    }
+    height_map = filter_heightmap(&height_map, &layer_kernel);
+    if let Some(path) = params.output_path("final.png") {
+        save_heightmap_as_png(&height_map, &path);
+    }
+
+    layer_kernel.size = 7;
+    layer_kernel.value = 2.0;
+    filter_heightmap(&height_map, &layer_kernel)
+}
+
+// aggregates params.particles more particles onto the grid, walkers are spawned
+// sequentially and walked in parallel batches against the grid as it was at the
+// start of the batch, so the result only depends on the seed and batch size
+fn populate_grid(
+    grid: &mut ParticleGrid,
+    params: &DiffusionLimitedAggregationParams,
+    scale: u32,
+    rng: &mut StdRng,
+) {
+    if grid.is_empty() {
+        error!("DLA grid has no particles to aggregate onto");
+        return;
+    }
+
+    let bar = ProgressBar::new(params.particles as u64);
+    let style = ProgressStyle::with_template(
+        "DLA layer: {bar:40} {percent}% | eta: {eta} elapsed: {elapsed} {pos:>7}/{len:7}",
+    )
+    .unwrap();
+    bar.set_style(style);
+
+    let mut cluster = Cluster::from_points(grid.points());
+    let mut placed = 0;
+    let mut failed_batches = 0;
+
+    while placed < params.particles as usize {
+        // walkers in a batch do not see each other, keeping batches small next to the
+        // aggregate keeps its shape close to sequential growth
+        let batch = (params.particles as usize - placed)
+            .min(params.walkers.max(1))
+            .min(grid.len() / 64 + 1);
+
+        let starts: Vec<(Point, u64)> = (0..batch)
+            .map(|_| {
+                let start = random_particle(
+                    params,
+                    scale,
+                    &cluster,
+                    |p| !grid.is_occupied(p.x as usize, p.y as usize),
+                    rng,
+                );
+                (start, rng.r#gen::<u64>())
+            })
+            .collect();
+
+        let mut added = 0;
+        for (point, parent) in grid.walk_batch(&starts, params.t).into_iter().flatten() {
+            // a walker may lose its spot to an earlier walker of the same batch
+            if grid.insert(point.x as usize, point.y as usize, parent) {
+                cluster.add(point);
+                added += 1;
+            }
+        }
 
-                kernel
+        placed += added;
+        bar.inc(added as u64);
+
+        if added == 0 {
+            failed_batches += 1;
+            if failed_batches > 100 {
+                error!("DLA walkers stopped sticking, {} particles placed", placed);
+                break;
             }
+        } else {
+            failed_batches = 0;
         }
     }
+
+    bar.finish();
 }
 
-pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
+// original single walker implementation on linked particles, kept as a reference
+// for benches/dla.rs
+#[cfg(any(test, feature = "bench"))]
+pub fn generate_linked(params: DiffusionLimitedAggregationParams) -> HeightMap {
     let scale_factor: u32 = 2;
     let height_scale = 100.0;
 
     let mut point_map: HashMap<(u32, u32), Particle> =
         HashMap::with_capacity(params.particles as usize);
-    let mut height_map: Vec<Vec<f32>> = vec![vec![1.0; params.width]; params.height];
+    let mut height_map = HeightMap::filled(params.width, params.height, 1.0);
+    let mut rng = StdRng::seed_from_u64(params.seed as u64);
+
+    if let Some(dir) = &params.output_dir
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        error!(
+            "Failed to create DLA output directory {}: {}",
+            dir.display(),
+            e
+        );
+    }
+
+    if let DiffusionLimitedAggregationMode::Image(mask) = &params.mode {
+        debug!("Adding mask particles to DLA image");
+        point_map = mask_particles(mask, params.width, params.height);
+    }
 
     debug!("Adding starting points to DLA image");
     for p in params.spawns.clone() {
@@ -238,6 +519,11 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
         layers: params.layers,
         density: params.density,
         kernel: params.kernel,
+        output_dir: params.output_dir.clone(),
+        seed: params.seed,
+        spawn_pattern: params.spawn_pattern.clone(),
+        mode: params.mode.clone(),
+        walkers: params.walkers,
     };
 
     debug!(
@@ -255,36 +541,36 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
     .unwrap();
     bar.set_style(style);
 
+    let mut cluster = Cluster::from_points(point_map.values().map(|p| p.point));
     for _ in 0..layer_params.particles {
         let pos = &random_particle(
-            layer_params.height as u32,
-            layer_params.width as u32,
-            &point_map,
+            &layer_params,
+            1,
+            &cluster,
+            |p| !point_map.contains_key(&p.key()),
+            &mut rng,
         );
-        walk(pos, &layer_params, &mut point_map);
+        cluster.add(walk(pos, &layer_params, &mut point_map, &mut rng));
         bar.inc(1);
     }
 
     bar.finish();
 
-    debug!("Saving layer image");
-    let mut img = RgbImage::new(layer_params.width as u32, layer_params.height as u32);
-    for each in point_map.keys() {
-        img.put_pixel(each.0 as u32, each.1 as u32, Rgb([255, 255, 255]));
+    if let Some(path) = params.output_path(&format!("layer_{}_particle.png", layer)) {
+        debug!("Saving layer image");
+        save_particle_map_as_png(point_map.values().map(|p| p.point), &layer_params, &path);
     }
-    let name = format!("./outputs/layer_{}_particle.png", 0);
-    let _ = img.save_with_format(name, image::ImageFormat::Png);
 
     // ========== heightmap from particle map  ==========
     let mut chain: HashMap<(u32, u32), bool> = HashMap::with_capacity(params.particles as usize);
     for each in point_map.values() {
-        height_map[each.point.y as usize][each.point.x as usize] = height_map
-            [each.point.y as usize][each.point.x as usize]
-            + (height_scale * gradient_growth_limited(each.height(&point_map, &mut chain)));
+        height_map[(each.point.x as usize, each.point.y as usize)] +=
+            height_scale * gradient_growth_limited(each.height(&point_map, &mut chain));
     }
 
-    let name = format!("./outputs/layer_{}_heightmap.png", layer);
-    save_heightmap_as_png(&height_map, &name);
+    if let Some(path) = params.output_path(&format!("layer_{}_heightmap.png", layer)) {
+        save_heightmap_as_png(&height_map, &path);
+    }
 
     for layer in 1..params.layers {
         // ========== scale heightmap ==========
@@ -297,9 +583,10 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
         }
         debug!("{} kernel size {}", layer, layer_kernel.size);
         debug!("{} kernel value {}", layer, layer_kernel.value);
-        height_map = filter_heightmap(height_map, layer_kernel.to_vec());
-        let name = format!("./outputs/layer_{}_heightmap_base.png", layer);
-        save_heightmap_as_png(&height_map, &name);
+        height_map = filter_heightmap(&height_map, &layer_kernel);
+        if let Some(path) = params.output_path(&format!("layer_{}_heightmap_base.png", layer)) {
+            save_heightmap_as_png(&height_map, &path);
+        }
 
         // ========== scale particle map ==========
         debug!("Scaling particle map");
@@ -320,6 +607,11 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
             layers: params.layers,
             density: params.density,
             kernel: params.kernel,
+            output_dir: params.output_dir.clone(),
+            seed: params.seed,
+            spawn_pattern: params.spawn_pattern.clone(),
+            mode: params.mode.clone(),
+            walkers: params.walkers,
         };
 
         debug!(
@@ -337,13 +629,16 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
         .unwrap();
         bar.set_style(style);
 
+        let mut cluster = Cluster::from_points(point_map.values().map(|p| p.point));
         for _ in 0..layer_params.particles {
             let pos = &random_particle(
-                layer_params.height as u32,
-                layer_params.width as u32,
-                &point_map,
+                &layer_params,
+                2_u32.pow(layer),
+                &cluster,
+                |p| !point_map.contains_key(&p.key()),
+                &mut rng,
             );
-            walk(pos, &layer_params, &mut point_map);
+            cluster.add(walk(pos, &layer_params, &mut point_map, &mut rng));
             bar.inc(1);
         }
 
@@ -355,21 +650,18 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
             HashMap::with_capacity(params.particles as usize);
         for each in point_map.values() {
             let h = height_scale * gradient_growth_limited(each.height(&point_map, &mut chain));
-            height_map[each.point.y as usize][each.point.x as usize] =
-                height_map[each.point.y as usize][each.point.x as usize] + h;
+            height_map[(each.point.x as usize, each.point.y as usize)] += h;
         }
 
         // ========== save images ==========
-        debug!("Saving layer image");
-        let mut img = RgbImage::new(layer_params.width as u32, layer_params.height as u32);
-        for each in point_map.keys() {
-            img.put_pixel(each.0 as u32, each.1 as u32, Rgb([255, 255, 255]));
+        if let Some(path) = params.output_path(&format!("layer_{}_particle.png", layer)) {
+            debug!("Saving layer image");
+            save_particle_map_as_png(point_map.values().map(|p| p.point), &layer_params, &path);
         }
-        let name = format!("./outputs/layer_{}_particle.png", layer);
-        let _ = img.save_with_format(name, image::ImageFormat::Png);
 
-        let name = format!("./outputs/layer_{}_heightmap_detailed.png", layer);
-        save_heightmap_as_png(&height_map, &name);
+        if let Some(path) = params.output_path(&format!("layer_{}_heightmap_detailed.png", layer)) {
+            save_heightmap_as_png(&height_map, &path);
+        }
 
         if layer == (params.layers - 1) {
             break;
@@ -377,7 +669,10 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
     }
 
     debug!("saving final heightmap");
-    height_map = scale_heightmap(&height_map);
+    // layer 0 is the base resolution, every further layer doubled it above
+    if params.layers > 0 {
+        height_map = scale_heightmap(&height_map);
+    }
     let mut layer_kernel = params.kernel.clone();
     layer_kernel.size = (params.height as f32 * 2_u32.pow(params.layers) as f32 / 30.0) as usize;
     if layer_kernel.size % 2 == 0 {
@@ -385,8 +680,10 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
     }
     debug!("final kernel size {}", layer_kernel.size);
     debug!("final kernel value {}", layer_kernel.value);
-    height_map = filter_heightmap(height_map, layer_kernel.to_vec());
-    save_heightmap_as_png(&height_map, "./outputs/final.png");
+    height_map = filter_heightmap(&height_map, &layer_kernel);
+    if let Some(path) = params.output_path("final.png") {
+        save_heightmap_as_png(&height_map, &path);
+    }
 
     // let height_map_scale = 50.0;
     // for row in height_map.iter_mut() {
@@ -400,33 +697,31 @@ pub fn generate(params: DiffusionLimitedAggregationParams) -> Vec<Vec<f32>> {
     layer_kernel.value = 2.0;
     debug!("Final kernel size {}", layer_kernel.size);
     debug!("Final kernel value {}", layer_kernel.value);
-    height_map = filter_heightmap(height_map, layer_kernel.to_vec());
+    height_map = filter_heightmap(&height_map, &layer_kernel);
 
     height_map
 }
 
-fn scale_heightmap(input: &Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-    let height = input.len();
-    let width = input[0].len();
-    let mut output = vec![vec![0.0; width * 2]; height * 2];
+fn scale_heightmap(input: &HeightMap) -> HeightMap {
+    let mut output = HeightMap::new(input.width() * 2, input.height() * 2);
 
-    for y in 0..height {
-        for x in 0..width {
-            let val = input[y][x];
-            output[y * 2][x * 2] = val;
-            output[y * 2][x * 2 + 1] = val;
-            output[y * 2 + 1][x * 2] = val;
-            output[y * 2 + 1][x * 2 + 1] = val;
-        }
+    for (x, z, val) in input.cells() {
+        output[(x * 2, z * 2)] = val;
+        output[(x * 2 + 1, z * 2)] = val;
+        output[(x * 2, z * 2 + 1)] = val;
+        output[(x * 2 + 1, z * 2 + 1)] = val;
     }
 
     output
 }
 
+#[cfg(any(test, feature = "bench"))]
 fn scale_particle_map(
     factor: u32,
     map: &HashMap<(u32, u32), Particle>,
 ) -> HashMap<(u32, u32), Particle> {
+    use std::collections::HashSet;
+
     fn mid(a: (u32, u32), b: (u32, u32)) -> (u32, u32) {
         let x = a.0 as i32 * 2 - (a.0 as i32 - b.0 as i32);
         let y = a.1 as i32 * 2 - (a.1 as i32 - b.1 as i32);
@@ -496,52 +791,196 @@ fn scale_particle_map(
     let mut new_map: HashMap<(u32, u32), Particle> = HashMap::new();
     let mut set: HashSet<(u32, u32)> = HashSet::new();
 
-    for k in map.values() {
-        if set.contains(&k.point.key()) {
+    // visit in key order, overlapping mid points make the result depend on it
+    let mut keys: Vec<(u32, u32)> = map.keys().copied().collect();
+    keys.sort_unstable();
+
+    for key in keys {
+        if set.contains(&key) {
             continue;
         }
 
-        scale_recursive(factor, k, map, &mut new_map, &mut set);
+        scale_recursive(factor, &map[&key], map, &mut new_map, &mut set);
     }
 
     return new_map;
 }
 
-fn random_particle(height: u32, width: u32, map: &HashMap<(u32, u32), Particle>) -> Point {
-    let mut rng = rand::thread_rng();
-    let spawn_mode = ParticleSpawnPattern::Random;
+// scale is the size of the current layer relative to the base layer
+fn random_particle(
+    params: &DiffusionLimitedAggregationParams,
+    scale: u32,
+    cluster: &Cluster,
+    is_free: impl Fn(Point) -> bool,
+    rng: &mut StdRng,
+) -> Point {
+    let width = params.width as u32;
+    let height = params.height as u32;
+    let clamp = |x: f32, y: f32| {
+        Point::new(
+            (x.round().max(0.0) as u32).min(width - 1),
+            (y.round().max(0.0) as u32).min(height - 1),
+        )
+    };
+
+    // crowded patterns first spread out, then give up and spawn anywhere
+    let mut i = 0;
+    loop {
+        let pattern = if i < 1000 {
+            &params.spawn_pattern
+        } else {
+            &ParticleSpawnPattern::Random
+        };
+
+        let current = match pattern {
+            ParticleSpawnPattern::Edge => match rng.gen_range(0..4) {
+                0 => Point::new(rng.gen_range(0..width), 0),
+                1 => Point::new(rng.gen_range(0..width), height - 1),
+                2 => Point::new(0, rng.gen_range(0..height)),
+                _ => Point::new(width - 1, rng.gen_range(0..height)),
+            },
+            ParticleSpawnPattern::Radius(weight) => {
+                let (cx, cy) = cluster.centre();
+                let r = weight * cluster.radius().max(1.0);
+                let angle = rng.gen_range(0.0..2.0 * PI);
+                clamp(cx + r * angle.cos(), cy + r * angle.sin())
+            }
+            ParticleSpawnPattern::Pattern(points) if !points.is_empty() => {
+                let (x, y) = pattern_point(points, scale, rng);
+                let spread = (i / 10) as f32;
+                if spread > 0.0 {
+                    clamp(
+                        x + rng.gen_range(-spread..=spread),
+                        y + rng.gen_range(-spread..=spread),
+                    )
+                } else {
+                    clamp(x, y)
+                }
+            }
+            ParticleSpawnPattern::Random | ParticleSpawnPattern::Pattern(_) => {
+                Point::new(rng.gen_range(0..width), rng.gen_range(0..height))
+            }
+        };
+
+        if is_free(current) {
+            return current;
+        }
 
-    match spawn_mode {
-        ParticleSpawnPattern::Edge => {
-            return Point::new(0, 0);
+        if i > 10000 {
+            error!("Particle failed to find new spot.");
+            return (0..height)
+                .flat_map(|y| (0..width).map(move |x| Point::new(x, y)))
+                .find(|p| is_free(*p))
+                .unwrap_or(Point::new(0, 0));
         }
-        ParticleSpawnPattern::Random | _ => {
-            let mut i = 0;
-            loop {
-                let current = Point::new(
-                    rng.gen_range(0..width) as u32,
-                    rng.gen_range(0..height) as u32,
-                );
+        i = i + 1;
+    }
+}
 
-                if !map.contains_key(&current.key()) {
-                    return current;
-                }
+// uniformly samples a position along the polyline through the scaled points
+fn pattern_point(points: &[Point], scale: u32, rng: &mut StdRng) -> (f32, f32) {
+    let scaled: Vec<(f32, f32)> = points
+        .iter()
+        .map(|p| ((p.x * scale) as f32, (p.y * scale) as f32))
+        .collect();
+    let lengths: Vec<f32> = scaled
+        .windows(2)
+        .map(|w| ((w[1].0 - w[0].0).powi(2) + (w[1].1 - w[0].1).powi(2)).sqrt())
+        .collect();
+    let total: f32 = lengths.iter().sum();
+
+    if total <= 0.0 {
+        return scaled[rng.gen_range(0..scaled.len())];
+    }
 
-                if i > 10000 {
-                    error!("Particle failed to find new spot.");
-                    return Point::new(0, 0);
-                }
-                i = i + 1;
+    let mut along = rng.gen_range(0.0..total);
+    for (i, length) in lengths.iter().enumerate() {
+        if along <= *length && *length > 0.0 {
+            let t = along / length;
+            let (a, b) = (scaled[i], scaled[i + 1]);
+            return (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+        }
+        along -= length;
+    }
+
+    scaled[scaled.len() - 1]
+}
+
+// builds linked particles from the bright pixels of a mask, pixels on the
+// boundary of the shape become leaves and links run towards its interior so
+// the ridge ends up along the middle of the shape
+fn mask_particles(mask: &GrayImage, width: usize, height: usize) -> HashMap<(u32, u32), Particle> {
+    let (width, height) = (width as u32, height as u32);
+    let mask = if mask.dimensions() == (width, height) {
+        mask.clone()
+    } else {
+        image::imageops::resize(mask, width, height, image::imageops::FilterType::Nearest)
+    };
+
+    let cords: [(i32, i32); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+    let filled = |x: i32, y: i32| {
+        x >= 0
+            && y >= 0
+            && x < width as i32
+            && y < height as i32
+            && mask.get_pixel(x as u32, y as u32)[0] > 127
+    };
+
+    // distance from the edge of the shape
+    let mut depth: HashMap<(u32, u32), u32> = HashMap::new();
+    let mut queue: VecDeque<(u32, u32)> = VecDeque::new();
+    for y in 0..height {
+        for x in 0..width {
+            let (xi, yi) = (x as i32, y as i32);
+            if filled(xi, yi) && cords.iter().any(|c| !filled(xi + c.0, yi + c.1)) {
+                depth.insert((x, y), 1);
+                queue.push_back((x, y));
+            }
+        }
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        let d = depth[&(x, y)];
+        for c in cords {
+            let (nx, ny) = (x as i32 + c.0, y as i32 + c.1);
+            if filled(nx, ny) && !depth.contains_key(&(nx as u32, ny as u32)) {
+                depth.insert((nx as u32, ny as u32), d + 1);
+                queue.push_back((nx as u32, ny as u32));
             }
         }
     }
+
+    let mut map: HashMap<(u32, u32), Particle> = depth
+        .keys()
+        .map(|&(x, y)| ((x, y), Particle::new(Point::new(x, y))))
+        .collect();
+
+    // each particle hangs off one deeper neighbour, local maxima are the roots
+    let mut keys: Vec<(u32, u32)> = depth.keys().copied().collect();
+    keys.sort_unstable();
+    for (x, y) in keys {
+        let d = depth[&(x, y)];
+        let parent = cords.iter().find_map(|c| {
+            let (nx, ny) = (x as i32 + c.0, y as i32 + c.1);
+            let key = (nx as u32, ny as u32);
+            (filled(nx, ny) && depth[&key] == d + 1).then_some(key)
+        });
+        if let Some(parent) = parent
+            && let Some(p) = map.get_mut(&parent)
+        {
+            p.link((x, y));
+        }
+    }
+
+    map
 }
 
+#[cfg(any(test, feature = "bench"))]
 fn walk(
     pos: &Point,
     params: &DiffusionLimitedAggregationParams,
     map: &mut HashMap<(u32, u32), Particle>,
-) {
+    rng: &mut StdRng,
+) -> Point {
     let mut current = pos.clone();
 
     if map.contains_key(&pos.key()) {
@@ -567,8 +1006,6 @@ fn walk(
     //     (1, -1),
     // ];
 
-    let mut rng = rand::thread_rng();
-
     loop {
         // check for connections
         let mut moves: Vec<Point> = Vec::with_capacity(cords.len());
@@ -598,12 +1035,12 @@ fn walk(
             }
 
             map.insert(new_particle.point.key(), new_particle);
-            break;
+            return current;
         }
 
         if p_cnt > 0 {
             let absorbtion_prob = absorbtion(params.t, cords.len() as u32, p_cnt);
-            let prob = rand::random::<f32>();
+            let prob = rng.r#gen::<f32>();
             if prob <= absorbtion_prob {
                 let new_particle: Particle = Particle::new(current.clone());
                 if let Some(l) = map.get_mut(&links[0].key()) {
@@ -611,7 +1048,7 @@ fn walk(
                 }
 
                 map.insert(new_particle.point.key(), new_particle);
-                break;
+                return current;
             } else {
             }
         }
@@ -625,31 +1062,12 @@ fn walk(
     }
 }
 
-fn filter_heightmap(input: Vec<Vec<f32>>, kernel: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-    let height = input.len();
-    let width = input[0].len();
-    let k = kernel.len();
-    let offset = k / 2;
-
-    let mut output = vec![vec![0.0; width]; height];
-
-    for y in 0..height {
-        for x in 0..width {
-            let mut sum = 0.0;
-            for ky in 0..k {
-                for kx in 0..k {
-                    let iy = y as isize + ky as isize - offset as isize;
-                    let ix = x as isize + kx as isize - offset as isize;
-                    if iy >= 0 && iy < height as isize && ix >= 0 && ix < width as isize {
-                        sum += input[iy as usize][ix as usize] * kernel[ky][kx];
-                    }
-                }
-            }
-            output[y][x] = sum;
-        }
-    }
-
-    output
+// layer kernels are always bumped to an odd size before they get here
+fn filter_heightmap(input: &HeightMap, kernel: &Kernel) -> HeightMap {
+    kernel
+        .to_filter()
+        .and_then(|filter| input.filter(&filter, BorderMode::Clamp))
+        .expect("Layer kernel size is odd")
 }
 
 fn gradient_growth_limited(x: f32) -> f32 {
@@ -668,39 +1086,52 @@ fn absorbtion(t: f32, a: u32, b: u32) -> f32 {
     return t.powi((a - b) as i32).min(1.0);
 }
 
-fn save_heightmap_as_png(height_map: &Vec<Vec<f32>>, filename: &str) {
-    let height = height_map.len();
-    let width = height_map[0].len();
-
-    let mut min_val = f32::MAX;
-    let mut max_val = f32::MIN;
+fn save_particle_map_as_png(
+    points: impl Iterator<Item = Point>,
+    params: &DiffusionLimitedAggregationParams,
+    path: &Path,
+) {
+    let mut img = RgbImage::new(params.width as u32, params.height as u32);
+    for each in points {
+        img.put_pixel(each.x, each.y, Rgb([255, 255, 255]));
+    }
 
-    for row in height_map {
-        for &val in row {
-            if val < min_val {
-                min_val = val;
-            }
-            if val > max_val {
-                max_val = val;
-            }
-        }
+    if let Err(e) = img.save_with_format(path, image::ImageFormat::Png) {
+        error!("Failed to save {}: {}", path.display(), e);
     }
+}
 
-    let range = if (max_val - min_val).abs() < std::f32::EPSILON {
-        1.0
-    } else {
-        max_val - min_val
-    };
+fn save_heightmap_as_png(height_map: &HeightMap, path: &Path) {
+    if let Err(e) = height_map.save_as(path, HeightMapFormat::Png16) {
+        error!("Failed to save {}: {:#}", path.display(), e);
+    }
+}
 
-    let mut img = GrayImage::new(width as u32, height as u32);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(seed: u32) -> Vec<f32> {
+        let params = DiffusionLimitedAggregationParams {
+            width: 16,
+            height: 16,
+            spawns: vec![Point::new(8, 8)],
+            particles: 60,
+            layers: 2,
+            walkers: 8,
+            seed,
+            ..Default::default()
+        };
+        generate(params).as_slice().to_vec()
+    }
 
-    for (y, row) in height_map.iter().enumerate() {
-        for (x, &val) in row.iter().enumerate() {
-            let norm = (val - min_val) / range;
-            let pixel_value = (norm * 255.0).round() as u8;
-            img.put_pixel(x as u32, y as u32, Luma([pixel_value]));
-        }
+    #[test]
+    fn same_seed_gives_same_heightmap() {
+        assert_eq!(run(5), run(5));
     }
 
-    let _ = img.save_with_format(filename, image::ImageFormat::Png);
+    #[test]
+    fn different_seeds_give_different_heightmaps() {
+        assert_ne!(run(5), run(6));
+    }
 }
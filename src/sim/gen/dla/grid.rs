@@ -0,0 +1,246 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+use super::{Particle, Point, absorbtion};
+
+const EMPTY: u32 = u32::MAX;
+const ROOT: u32 = u32::MAX - 1;
+
+// side length of the coarse occupancy blocks, walkers far from any particle
+// jump up to half a block at a time instead of stepping cell by cell
+const BLOCK: usize = 8;
+
+// walkers that have not stuck after this many steps are dropped
+const MAX_WALK_STEPS: u32 = 1_000_000;
+
+// *   3   *
+// 0   x   2
+// *   1   *
+const CORDS: [(i32, i32); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+
+// dense particle map, each occupied cell stores the index of the cell it attached to
+pub(super) struct ParticleGrid {
+    pub width: usize,
+    pub height: usize,
+    parent: Vec<u32>,
+    blocks: Vec<u32>, // occupied cells per block
+    count: usize,
+}
+
+impl ParticleGrid {
+    pub fn new(width: usize, height: usize) -> Self {
+        assert!(
+            width * height < ROOT as usize,
+            "DLA grid of {}x{} is too large",
+            width,
+            height
+        );
+
+        ParticleGrid {
+            width,
+            height,
+            parent: vec![EMPTY; width * height],
+            blocks: vec![0; width.div_ceil(BLOCK) * height.div_ceil(BLOCK)],
+            count: 0,
+        }
+    }
+
+    // converts linked particles, a particle links to the particles that attached to it
+    pub fn from_map(map: &HashMap<(u32, u32), Particle>, width: usize, height: usize) -> Self {
+        let mut grid = ParticleGrid::new(width, height);
+
+        let mut keys: Vec<(u32, u32)> = map.keys().copied().collect();
+        keys.sort_unstable();
+        for key in &keys {
+            grid.insert(key.0 as usize, key.1 as usize, ROOT);
+        }
+        for key in &keys {
+            let parent = grid.index(key.0 as usize, key.1 as usize) as u32;
+            for &(x, y) in &map[key].linked {
+                if (x as usize) < width && (y as usize) < height {
+                    let i = grid.index(x as usize, y as usize);
+                    if grid.parent[i] == ROOT {
+                        grid.parent[i] = parent;
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    pub fn is_occupied(&self, x: usize, y: usize) -> bool {
+        self.parent[self.index(x, y)] != EMPTY
+    }
+
+    // returns false when the cell is already taken
+    pub fn insert(&mut self, x: usize, y: usize, parent: u32) -> bool {
+        let i = self.index(x, y);
+        if self.parent[i] != EMPTY {
+            return false;
+        }
+
+        self.parent[i] = parent;
+        self.blocks[(y / BLOCK) * self.width.div_ceil(BLOCK) + x / BLOCK] += 1;
+        self.count += 1;
+        true
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = Point> + '_ {
+        self.parent
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p != EMPTY)
+            .map(|(i, _)| Point::new((i % self.width) as u32, (i / self.width) as u32))
+    }
+
+    // true when there may be a particle within a block of the cell
+    fn near_particles(&self, x: usize, y: usize) -> bool {
+        let blocks_wide = self.width.div_ceil(BLOCK);
+        let blocks_high = self.height.div_ceil(BLOCK);
+        let (bx, by) = (x / BLOCK, y / BLOCK);
+
+        for ny in by.saturating_sub(1)..(by + 2).min(blocks_high) {
+            for nx in bx.saturating_sub(1)..(bx + 2).min(blocks_wide) {
+                if self.blocks[ny * blocks_wide + nx] > 0 {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    // doubles the grid, every link gains a particle at its mid point
+    pub fn scale(&self) -> ParticleGrid {
+        let mut scaled = ParticleGrid::new(self.width * 2, self.height * 2);
+
+        for (i, &parent) in self.parent.iter().enumerate() {
+            if parent == EMPTY {
+                continue;
+            }
+
+            let (x, y) = (i % self.width, i / self.width);
+            if parent == ROOT {
+                scaled.insert(x * 2, y * 2, ROOT);
+                continue;
+            }
+
+            let (px, py) = (parent as usize % self.width, parent as usize / self.width);
+            let (mx, my) = (x + px, y + py);
+            let mid = scaled.index(mx, my) as u32;
+            let scaled_parent = scaled.index(px * 2, py * 2) as u32;
+            scaled.insert(x * 2, y * 2, mid);
+            scaled.insert(mx, my, scaled_parent);
+        }
+
+        scaled
+    }
+
+    // longest chain of attached particles below each cell, leaves are 1 and empty cells 0
+    pub fn depths(&self) -> Vec<u32> {
+        let mut pending = vec![0u32; self.parent.len()];
+        for &p in &self.parent {
+            if p < ROOT {
+                pending[p as usize] += 1;
+            }
+        }
+
+        let mut depth = vec![0u32; self.parent.len()];
+        let mut stack: Vec<usize> = (0..self.parent.len())
+            .filter(|&i| self.parent[i] != EMPTY && pending[i] == 0)
+            .collect();
+
+        while let Some(i) = stack.pop() {
+            depth[i] = depth[i].max(1);
+
+            let p = self.parent[i];
+            if p < ROOT {
+                let p = p as usize;
+                depth[p] = depth[p].max(depth[i] + 1);
+                pending[p] -= 1;
+                if pending[p] == 0 {
+                    stack.push(p);
+                }
+            }
+        }
+
+        depth
+    }
+
+    // walks every start against the current grid in parallel and returns where
+    // each walker stuck, results only depend on the starts and their seeds
+    pub fn walk_batch(&self, starts: &[(Point, u64)], t: f32) -> Vec<Option<(Point, u32)>> {
+        starts
+            .par_iter()
+            .map(|&(start, seed)| self.walk(start, t, &mut StdRng::seed_from_u64(seed)))
+            .collect()
+    }
+
+    fn walk(&self, start: Point, t: f32, rng: &mut StdRng) -> Option<(Point, u32)> {
+        let (mut x, mut y) = (start.x as usize, start.y as usize);
+        if self.is_occupied(x, y) {
+            return None;
+        }
+
+        let half = (BLOCK / 2) as i32;
+        for _ in 0..MAX_WALK_STEPS {
+            if !self.near_particles(x, y) {
+                let jx = x as i32 + rng.gen_range(-half..=half);
+                let jy = y as i32 + rng.gen_range(-half..=half);
+                x = jx.clamp(0, self.width as i32 - 1) as usize;
+                y = jy.clamp(0, self.height as i32 - 1) as usize;
+                continue;
+            }
+
+            let mut moves = [(0usize, 0usize); 4];
+            let mut move_cnt = 0;
+            let mut link = None;
+            let mut p_cnt: u32 = 0;
+
+            for c in CORDS {
+                let nx = x as i32 + c.0;
+                let ny = y as i32 + c.1;
+                if nx < 0 || ny < 0 || nx >= self.width as i32 || ny >= self.height as i32 {
+                    continue;
+                }
+
+                let (nx, ny) = (nx as usize, ny as usize);
+                if self.is_occupied(nx, ny) {
+                    p_cnt += 1;
+                    link.get_or_insert(self.index(nx, ny) as u32);
+                } else {
+                    moves[move_cnt] = (nx, ny);
+                    move_cnt += 1;
+                }
+            }
+
+            if let Some(link) = link
+                && (p_cnt >= CORDS.len() as u32
+                    || rng.r#gen::<f32>() <= absorbtion(t, CORDS.len() as u32, p_cnt))
+            {
+                return Some((Point::new(x as u32, y as u32), link));
+            }
+
+            if move_cnt > 0 {
+                (x, y) = moves[rng.gen_range(0..move_cnt)];
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::{Result, ensure};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// how samples outside the map are read
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BorderMode {
+    Zero, // outside is 0, darkens the edges of a blur
+    #[default]
+    Clamp, // repeat the edge value
+    Mirror, // reflect about the edge cell, -1 reads 1
+    Wrap, // read from the opposite edge, for tiling maps
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    // normalised gaussian truncated to radius cells
+    Gaussian {
+        sigma: f32,
+        radius: usize,
+    },
+    // normalised mean over a (2 * radius + 1) square
+    Box {
+        radius: usize,
+    },
+    // normalised gaussian stretched along angle (degrees, 0 is +x)
+    Anisotropic {
+        sigma_along: f32,
+        sigma_across: f32,
+        angle: f32,
+        radius: usize,
+    },
+    // kernel given by the outer product column[y] * row[x], both of odd length
+    Separable {
+        column: Vec<f32>,
+        row: Vec<f32>,
+    },
+    // any square kernel of odd size, indexed [y][x]
+    Kernel(Vec<Vec<f32>>),
+}
+
+impl Filter {
+    pub fn gaussian(sigma: f32) -> Self {
+        Filter::Gaussian {
+            sigma,
+            radius: (sigma * 3.0).ceil().max(1.0) as usize,
+        }
+    }
+
+    // kernels convolve around their centre cell, so every side must be odd
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Filter::Separable { column, row } => ensure!(
+                column.len() % 2 == 1 && row.len() % 2 == 1,
+                "Separable kernel is {}x{}, both sizes must be odd",
+                row.len(),
+                column.len()
+            ),
+            Filter::Kernel(kernel) => {
+                let k = kernel.len();
+                ensure!(
+                    k % 2 == 1 && kernel.iter().all(|r| r.len() == k),
+                    "Kernel must be square with an odd size, got {} rows of {:?} values",
+                    k,
+                    kernel.iter().map(|r| r.len()).collect::<Vec<_>>()
+                );
+            }
+            Filter::Gaussian { .. } | Filter::Box { .. } | Filter::Anisotropic { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    // the full 2d kernel, indexed [y][x]
+    pub fn kernel(&self) -> Vec<Vec<f32>> {
+        if let Some((column, row)) = self.separate() {
+            return column
+                .iter()
+                .map(|c| row.iter().map(|r| c * r).collect())
+                .collect();
+        }
+
+        match self {
+            Filter::Anisotropic {
+                sigma_along,
+                sigma_across,
+                angle,
+                radius,
+            } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let r = *radius as isize;
+                let mut kernel: Vec<Vec<f32>> = (-r..=r)
+                    .map(|dy| {
+                        (-r..=r)
+                            .map(|dx| {
+                                let along = dx as f32 * cos + dy as f32 * sin;
+                                let across = -(dx as f32) * sin + dy as f32 * cos;
+                                f32::exp(
+                                    -0.5 * (along * along / (sigma_along * sigma_along).max(1e-6)
+                                        + across * across
+                                            / (sigma_across * sigma_across).max(1e-6)),
+                                )
+                            })
+                            .collect()
+                    })
+                    .collect();
+
+                let sum: f32 = kernel.iter().flatten().sum();
+                for v in kernel.iter_mut().flatten() {
+                    *v /= sum;
+                }
+                kernel
+            }
+            Filter::Kernel(kernel) => kernel.clone(),
+            _ => unreachable!("separable filters are handled above"),
+        }
+    }
+
+    // column and row weights whose outer product is the kernel, if the filter has them
+    pub fn separate(&self) -> Option<(Vec<f32>, Vec<f32>)> {
+        match self {
+            Filter::Gaussian { sigma, radius } => {
+                let weights = gaussian_weights(*sigma, *radius);
+                Some((weights.clone(), weights))
+            }
+            Filter::Box { radius } => {
+                let weights = vec![1.0 / (2 * radius + 1) as f32; 2 * radius + 1];
+                Some((weights.clone(), weights))
+            }
+            Filter::Separable { column, row } => Some((column.clone(), row.clone())),
+            Filter::Anisotropic { .. } | Filter::Kernel(_) => None,
+        }
+    }
+}
+
+pub fn gaussian_weights(sigma: f32, radius: usize) -> Vec<f32> {
+    let r = radius as isize;
+    let weights: Vec<f32> = (-r..=r)
+        .map(|d| {
+            let d = d as f32;
+            f32::exp(-(d * d) / (2.0 * sigma * sigma).max(1e-6))
+        })
+        .collect();
+    let sum: f32 = weights.iter().sum();
+
+    weights.iter().map(|w| w / sum).collect()
+}
+
+// maps a possibly out of range coordinate onto the map, None reads as 0
+fn border_index(i: isize, n: usize, border: BorderMode) -> Option<usize> {
+    let len = n as isize;
+    if (0..len).contains(&i) {
+        return Some(i as usize);
+    }
+
+    match border {
+        BorderMode::Zero => None,
+        BorderMode::Clamp => Some(i.clamp(0, len - 1) as usize),
+        BorderMode::Wrap => Some(i.rem_euclid(len) as usize),
+        BorderMode::Mirror => {
+            if n == 1 {
+                return Some(0);
+            }
+            let period = 2 * (len - 1);
+            let m = i.rem_euclid(period);
+            Some(if m >= len { period - m } else { m } as usize)
+        }
+    }
+}
+
+// filters a row major map of width x height values
+pub fn convolve(
+    values: &[f32],
+    width: usize,
+    height: usize,
+    filter: &Filter,
+    border: BorderMode,
+) -> Result<Vec<f32>> {
+    assert_eq!(values.len(), width * height);
+    filter.validate()?;
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(match filter.separate() {
+        Some((column, row)) => convolve_separable(values, width, height, &column, &row, border),
+        None => convolve_2d(values, width, height, &filter.kernel(), border),
+    })
+}
+
+fn convolve_separable(
+    values: &[f32],
+    width: usize,
+    height: usize,
+    column: &[f32],
+    row: &[f32],
+    border: BorderMode,
+) -> Vec<f32> {
+    let row_offset = (row.len() / 2) as isize;
+    let column_offset = (column.len() / 2) as isize;
+
+    let mut horizontal = vec![0.0; values.len()];
+    horizontal
+        .par_chunks_mut(width)
+        .zip(values.par_chunks(width))
+        .for_each(|(out, line)| {
+            for (x, o) in out.iter_mut().enumerate() {
+                *o = row
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(k, w)| {
+                        border_index(x as isize + k as isize - row_offset, width, border)
+                            .map(|i| line[i] * w)
+                    })
+                    .sum();
+            }
+        });
+
+    let mut output = vec![0.0; values.len()];
+    output
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, out)| {
+            for (k, w) in column.iter().enumerate() {
+                let Some(sy) =
+                    border_index(y as isize + k as isize - column_offset, height, border)
+                else {
+                    continue;
+                };
+                let line = &horizontal[sy * width..(sy + 1) * width];
+                for (o, v) in out.iter_mut().zip(line) {
+                    *o += v * w;
+                }
+            }
+        });
+
+    output
+}
+
+fn convolve_2d(
+    values: &[f32],
+    width: usize,
+    height: usize,
+    kernel: &[Vec<f32>],
+    border: BorderMode,
+) -> Vec<f32> {
+    let offset = (kernel.len() / 2) as isize;
+
+    let mut output = vec![0.0; values.len()];
+    output
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, out)| {
+            for (x, o) in out.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (ky, kernel_row) in kernel.iter().enumerate() {
+                    let Some(sy) = border_index(y as isize + ky as isize - offset, height, border)
+                    else {
+                        continue;
+                    };
+                    for (kx, w) in kernel_row.iter().enumerate() {
+                        if let Some(sx) =
+                            border_index(x as isize + kx as isize - offset, width, border)
+                        {
+                            sum += values[sy * width + sx] * w;
+                        }
+                    }
+                }
+                *o = sum;
+            }
+        });
+
+    output
+}
@@ -0,0 +1,471 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use exr::prelude::{
+    AnyChannel, AnyChannels, AttributeValue, Encoding, FlatSamples, Image, Layer, LayerAttributes,
+    SmallVec, Text, WritableImage,
+};
+use serde::{Deserialize, Serialize};
+
+use super::lib::{HeightMap, HeightMapMesh};
+
+const SCALE_KEY: &str = "height_scale";
+const OFFSET_KEY: &str = "height_offset";
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeightMapFormat {
+    Png16, // 16 bit grayscale png, scale and offset in text chunks
+    Tiff,  // 32 bit float grayscale tiff, scale and offset in the image description
+    Exr,   // single channel Y f32 exr, scale and offset as layer attributes
+    R16,   // raw little endian u16 with a json sidecar
+    R32,   // raw little endian f32 with a json sidecar
+}
+
+// the float formats (Tiff, Exr and R32) store absolute heights and always write
+// scale 1 and offset 0. The metadata is still read back so files from tools that
+// normalise their floats load at the right heights
+
+// a stored value v is the height offset + v * scale, integer formats store v in 0..1
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct VerticalScale {
+    pub scale: f32,
+    pub offset: f32,
+}
+
+// describes a raw file, written next to it as <file>.json
+#[derive(Serialize, Deserialize)]
+struct RawSidecar {
+    width: usize,
+    height: usize,
+    #[serde(flatten)]
+    vertical: VerticalScale,
+}
+
+impl Default for VerticalScale {
+    fn default() -> Self {
+        VerticalScale {
+            scale: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl VerticalScale {
+    // spans the map's heights so quantising keeps as much precision as possible
+    pub fn fit(map: &HeightMap) -> Self {
+        match map.bounds() {
+            Some((min, max)) if max > min => VerticalScale {
+                scale: max - min,
+                offset: min,
+            },
+            Some((min, _)) => VerticalScale {
+                scale: 1.0,
+                offset: min,
+            },
+            None => VerticalScale::default(),
+        }
+    }
+
+    fn quantise(&self, h: f32) -> u16 {
+        ((h - self.offset) / self.scale * u16::MAX as f32)
+            .round()
+            .clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn dequantise(&self, v: u16) -> f32 {
+        self.offset + v as f32 / u16::MAX as f32 * self.scale
+    }
+}
+
+impl HeightMapFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        Ok(match extension.as_deref() {
+            Some("png") => HeightMapFormat::Png16,
+            Some("tif" | "tiff") => HeightMapFormat::Tiff,
+            Some("exr") => HeightMapFormat::Exr,
+            Some("r16") => HeightMapFormat::R16,
+            Some("r32") => HeightMapFormat::R32,
+            _ => bail!("Unknown heightmap format for {}", path.display()),
+        })
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HeightMapFormat::Png16 => "png",
+            HeightMapFormat::Tiff => "tiff",
+            HeightMapFormat::Exr => "exr",
+            HeightMapFormat::R16 => "r16",
+            HeightMapFormat::R32 => "r32",
+        }
+    }
+}
+
+impl HeightMap {
+    // the format is picked from the file extension
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.save_as(path, HeightMapFormat::from_path(path)?)
+    }
+
+    pub fn save_as(&self, path: impl AsRef<Path>, format: HeightMapFormat) -> Result<()> {
+        let path = path.as_ref();
+        let result = match format {
+            HeightMapFormat::Png16 => save_png16(self, path),
+            HeightMapFormat::Tiff => save_tiff(self, path),
+            HeightMapFormat::Exr => save_exr(self, path),
+            HeightMapFormat::R16 => save_raw(self, path, true),
+            HeightMapFormat::R32 => save_raw(self, path, false),
+        };
+        result.with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<HeightMap> {
+        let path = path.as_ref();
+        HeightMap::load_as(path, HeightMapFormat::from_path(path)?)
+    }
+
+    pub fn load_as(path: impl AsRef<Path>, format: HeightMapFormat) -> Result<HeightMap> {
+        let path = path.as_ref();
+        let result = match format {
+            HeightMapFormat::Png16 => std::fs::read(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| decode_png(&data, VerticalScale::default())),
+            HeightMapFormat::Tiff => load_tiff(path),
+            HeightMapFormat::Exr => load_exr(path),
+            HeightMapFormat::R16 => load_raw(path, true),
+            HeightMapFormat::R32 => load_raw(path, false),
+        };
+        result.with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+fn save_png16(map: &HeightMap, path: &Path) -> Result<()> {
+    let vertical = VerticalScale::fit(map);
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        map.width() as u32,
+        map.height() as u32,
+    );
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    encoder.add_text_chunk(SCALE_KEY.to_string(), vertical.scale.to_string())?;
+    encoder.add_text_chunk(OFFSET_KEY.to_string(), vertical.offset.to_string())?;
+
+    // png samples are big endian
+    let data: Vec<u8> = map
+        .iter()
+        .flat_map(|&h| vertical.quantise(h).to_be_bytes())
+        .collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+// any png the image crate can read, pngs without scale metadata use fallback
+pub fn decode_png(data: &[u8], fallback: VerticalScale) -> Result<HeightMap> {
+    let reader = png::Decoder::new(data).read_info()?;
+    let text = &reader.info().uncompressed_latin1_text;
+    let value = |key: &str| -> Result<Option<f32>> {
+        match text.iter().find(|t| t.keyword == key) {
+            Some(t) => {
+                Ok(Some(t.text.trim().parse().with_context(|| {
+                    format!("Invalid {} '{}'", key, t.text)
+                })?))
+            }
+            None => Ok(None),
+        }
+    };
+    let vertical = VerticalScale {
+        scale: value(SCALE_KEY)?.unwrap_or(fallback.scale),
+        offset: value(OFFSET_KEY)?.unwrap_or(fallback.offset),
+    };
+
+    let img = image::load_from_memory_with_format(data, image::ImageFormat::Png)?.to_luma16();
+    let (width, height) = img.dimensions();
+    HeightMap::from_vec(
+        width as usize,
+        height as usize,
+        img.pixels().map(|p| vertical.dequantise(p.0[0])).collect(),
+    )
+}
+
+fn save_tiff(map: &HeightMap, path: &Path) -> Result<()> {
+    let vertical = VerticalScale::default();
+    let mut encoder = tiff::encoder::TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let mut image = encoder.new_image::<tiff::encoder::colortype::Gray32Float>(
+        map.width() as u32,
+        map.height() as u32,
+    )?;
+    image.encoder().write_tag(
+        tiff::tags::Tag::ImageDescription,
+        format!(
+            "{}={};{}={}",
+            SCALE_KEY, vertical.scale, OFFSET_KEY, vertical.offset
+        )
+        .as_str(),
+    )?;
+    image.write_data(map.as_slice())?;
+
+    Ok(())
+}
+
+fn load_tiff(path: &Path) -> Result<HeightMap> {
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+
+    // the description is key=value pairs separated by ;, unknown keys are ignored
+    let mut vertical = VerticalScale::default();
+    if let Ok(description) = decoder.get_tag_ascii_string(tiff::tags::Tag::ImageDescription) {
+        for pair in description.split(';') {
+            match pair.split_once('=') {
+                Some((SCALE_KEY, v)) => vertical.scale = v.trim().parse()?,
+                Some((OFFSET_KEY, v)) => vertical.offset = v.trim().parse()?,
+                _ => {}
+            }
+        }
+    }
+
+    let values = match decoder.read_image()? {
+        tiff::decoder::DecodingResult::F32(values) => values,
+        _ => bail!("Expected a 32 bit float grayscale tiff"),
+    };
+    ensure!(
+        values.len() == width as usize * height as usize,
+        "Expected a single channel tiff"
+    );
+
+    HeightMap::from_vec(
+        width as usize,
+        height as usize,
+        values
+            .into_iter()
+            .map(|v| vertical.offset + v * vertical.scale)
+            .collect(),
+    )
+}
+
+fn save_exr(map: &HeightMap, path: &Path) -> Result<()> {
+    let vertical = VerticalScale::default();
+    let mut attributes = LayerAttributes::named("height");
+    attributes
+        .other
+        .insert(Text::from(SCALE_KEY), AttributeValue::F32(vertical.scale));
+    attributes
+        .other
+        .insert(Text::from(OFFSET_KEY), AttributeValue::F32(vertical.offset));
+
+    let channel = AnyChannel::new("Y", FlatSamples::F32(map.as_slice().to_vec()));
+    let layer = Layer::new(
+        (map.width(), map.height()),
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(SmallVec::from_vec(vec![channel])),
+    );
+    Image::from_layer(layer).write().to_file(path)?;
+
+    Ok(())
+}
+
+fn load_exr(path: &Path) -> Result<HeightMap> {
+    let image = exr::prelude::read_first_flat_layer_from_file(path)?;
+    let layer = image.layer_data;
+    let channel = layer
+        .channel_data
+        .list
+        .iter()
+        .find(|c| c.name == *"Y")
+        .or(layer.channel_data.list.first())
+        .context("Exr has no channels")?;
+
+    let attribute = |key: &str, default: f32| match layer.attributes.other.get(&Text::from(key)) {
+        Some(AttributeValue::F32(v)) => *v,
+        _ => default,
+    };
+    let vertical = VerticalScale {
+        scale: attribute(SCALE_KEY, 1.0),
+        offset: attribute(OFFSET_KEY, 0.0),
+    };
+
+    HeightMap::from_vec(
+        layer.size.width(),
+        layer.size.height(),
+        channel
+            .sample_data
+            .values_as_f32()
+            .map(|v| vertical.offset + v * vertical.scale)
+            .collect(),
+    )
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+fn save_raw(map: &HeightMap, path: &Path, quantise: bool) -> Result<()> {
+    let vertical = if quantise {
+        VerticalScale::fit(map)
+    } else {
+        VerticalScale::default()
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    for &h in map {
+        if quantise {
+            writer.write_all(&vertical.quantise(h).to_le_bytes())?;
+        } else {
+            writer.write_all(&h.to_le_bytes())?;
+        }
+    }
+    writer.flush()?;
+
+    let sidecar = RawSidecar {
+        width: map.width(),
+        height: map.height(),
+        vertical,
+    };
+    std::fs::write(sidecar_path(path), serde_json::to_string_pretty(&sidecar)?)?;
+
+    Ok(())
+}
+
+fn load_raw(path: &Path, quantised: bool) -> Result<HeightMap> {
+    let sidecar_path = sidecar_path(path);
+    let sidecar: RawSidecar = serde_json::from_str(
+        &std::fs::read_to_string(&sidecar_path)
+            .with_context(|| format!("Missing raw sidecar {}", sidecar_path.display()))?,
+    )?;
+
+    let mut data = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+    let sample_size = if quantised { 2 } else { 4 };
+    ensure!(
+        data.len() == sidecar.width * sidecar.height * sample_size,
+        "Expected {}x{} samples of {} bytes, file is {} bytes",
+        sidecar.width,
+        sidecar.height,
+        sample_size,
+        data.len()
+    );
+
+    let vertical = sidecar.vertical;
+    let values = if quantised {
+        data.chunks_exact(2)
+            .map(|b| vertical.dequantise(u16::from_le_bytes([b[0], b[1]])))
+            .collect()
+    } else {
+        data.chunks_exact(4)
+            .map(|b| {
+                vertical.offset + f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * vertical.scale
+            })
+            .collect()
+    };
+
+    HeightMap::from_vec(sidecar.width, sidecar.height, values)
+}
+
+// wavefront obj of a heightmap mesh, one vertex per cell
+pub fn save_obj(mesh: &HeightMapMesh, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    for v in &mesh.vertices {
+        writeln!(writer, "v {} {} {}", v[0], v[1], v[2])?;
+    }
+    for uv in &mesh.uvs {
+        writeln!(writer, "vt {} {}", uv[0], uv[1])?;
+    }
+    for n in &mesh.normals {
+        writeln!(writer, "vn {} {} {}", n[0], n[1], n[2])?;
+    }
+    // obj indices start at 1, every vertex has a uv and normal of the same index
+    for face in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [face[0] + 1, face[1] + 1, face[2] + 1];
+        writeln!(writer, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> HeightMap {
+        let values = (0..7 * 5)
+            .map(|i| (i as f32 * 0.37).sin() * 8.0 - 2.0)
+            .collect();
+        HeightMap::from_vec(7, 5, values).unwrap()
+    }
+
+    fn round_trip(format: HeightMapFormat) -> HeightMap {
+        let dir = std::env::temp_dir().join(format!("heightmap_io_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("map.{}", format.extension()));
+
+        map().save_as(&path, format).unwrap();
+        HeightMap::load_as(&path, format).unwrap()
+    }
+
+    fn max_error(a: &HeightMap, b: &HeightMap) -> f32 {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn float_formats_round_trip_exactly() {
+        for format in [
+            HeightMapFormat::Tiff,
+            HeightMapFormat::Exr,
+            HeightMapFormat::R32,
+        ] {
+            assert_eq!(round_trip(format), map(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn quantised_formats_round_trip_within_a_step() {
+        let (min, max) = map().bounds().unwrap();
+        let step = (max - min) / u16::MAX as f32;
+        for format in [HeightMapFormat::Png16, HeightMapFormat::R16] {
+            let loaded = round_trip(format);
+            assert!(max_error(&loaded, &map()) <= step, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn png_keeps_scale_and_offset() {
+        let dir = std::env::temp_dir().join(format!("heightmap_png_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("map.png");
+        map().save_as(&path, HeightMapFormat::Png16).unwrap();
+
+        // the stored metadata wins over the fallback for pngs without any
+        let fallback = VerticalScale {
+            scale: 10.0,
+            offset: 0.0,
+        };
+        let loaded = decode_png(&std::fs::read(&path).unwrap(), fallback).unwrap();
+        let (min, max) = map().bounds().unwrap();
+        let (loaded_min, loaded_max) = loaded.bounds().unwrap();
+        assert_eq!(loaded_min, min);
+        assert!((loaded_max - max).abs() <= (max - min) * 1e-6);
+    }
+}
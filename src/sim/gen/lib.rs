@@ -1,16 +1,24 @@
-use std::ops::Add;
+use std::ops::{Index, IndexMut};
 
-use super::{dla, perlin};
+use super::filter::{self, BorderMode, Filter};
+use super::warp::DomainWarp;
+use super::{dla, perlin, simplex, worley};
+use crate::assets::TerrainHeights;
+use crate::sim::terrain::Terrain;
 use cgmath::{InnerSpace, Vector3};
+use rayon::prelude::*;
 
-const NORMAL_Y_COMPONENT: f32 = 2.0;
-
+// row major heights, the value at (x, z) is stored at z * width + x to match
+// Terrain and TerrainHeights
+#[derive(Clone, Debug, PartialEq)]
 pub struct HeightMap {
-    pub map: Vec<Vec<f32>>,
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
 }
 
 pub struct HeightMapMesh {
-    pub vertices: Vec<[f32; 3]>,
+    pub vertices: Vec<[f32; 3]>, // [x, height, z] in cells, the same frame as TerrainHeights
     pub normals: Vec<[f32; 3]>,
     pub uvs: Vec<[f32; 2]>,
     pub indices: Vec<u32>,
@@ -20,13 +28,81 @@ pub enum Algorithms {
     FractalPerlin(perlin::FractalPerlinParams),
     GradientFractalPerlin(perlin::GradientFractalPerlinParams),
     DiffusionLimitedAggregation(dla::DiffusionLimitedAggregationParams),
+    Simplex(simplex::SimplexParams),
+    Worley(worley::WorleyParams),
 }
 
 impl HeightMap {
     pub fn new(width: usize, height: usize) -> HeightMap {
-        return HeightMap {
-            map: vec![vec![0.0; width]; height],
-        };
+        HeightMap::filled(width, height, 0.0)
+    }
+
+    pub fn filled(width: usize, height: usize, value: f32) -> HeightMap {
+        HeightMap {
+            width,
+            height,
+            data: vec![value; width * height],
+        }
+    }
+
+    pub fn from_vec(width: usize, height: usize, data: Vec<f32>) -> anyhow::Result<HeightMap> {
+        anyhow::ensure!(
+            data.len() == width * height,
+            "Expected {}x{} = {} heights, got {}",
+            width,
+            height,
+            width * height,
+            data.len()
+        );
+
+        Ok(HeightMap {
+            width,
+            height,
+            data,
+        })
+    }
+
+    // rows are indexed by z, every row must have the same length
+    pub fn from_rows(rows: &[Vec<f32>]) -> anyhow::Result<HeightMap> {
+        let width = rows.first().map_or(0, |r| r.len());
+        anyhow::ensure!(
+            rows.iter().all(|r| r.len() == width),
+            "Heightmap rows have different lengths"
+        );
+
+        HeightMap::from_vec(width, rows.len(), rows.concat())
+    }
+
+    pub fn from_terrain(terrain: &Terrain) -> HeightMap {
+        HeightMap {
+            width: terrain.width,
+            height: terrain.height,
+            data: terrain.extract_heights(),
+        }
+    }
+
+    // a single layer terrain of material_id, fails on negative heights since
+    // terrain layers start at 0, see Terrain::from_heights
+    pub fn to_terrain(&self, material_id: u16) -> anyhow::Result<Terrain> {
+        Terrain::from_heights(self.width, self.height, &self.data, material_id)
+    }
+
+    pub fn apply_to_terrain(&self, terrain: &mut Terrain) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            terrain.width == self.width && terrain.height == self.height,
+            "Heightmap is {}x{} but terrain is {}x{}",
+            self.width,
+            self.height,
+            terrain.width,
+            terrain.height
+        );
+        anyhow::ensure!(
+            self.data.iter().all(|&h| h >= 0.0),
+            "Heightmap has negative heights, terrain layers start at 0"
+        );
+        terrain.apply_heights(&self.data);
+
+        Ok(())
     }
 
     pub fn generate(algorithm: Algorithms) -> HeightMap {
@@ -40,73 +116,191 @@ impl HeightMap {
             Algorithms::DiffusionLimitedAggregation(diffusion_limited_aggregation_params) => {
                 generate_diff_lim_agg(diffusion_limited_aggregation_params)
             }
+            Algorithms::Simplex(simplex_params) => generate_simplex(simplex_params),
+            Algorithms::Worley(worley_params) => generate_worley(worley_params),
         }
     }
 
-    pub fn load(values: &[Vec<f32>]) -> HeightMap {
-        HeightMap {
-            map: values.to_vec(),
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, x: usize, z: usize) -> Option<f32> {
+        if x < self.width && z < self.height {
+            Some(self.data[z * self.width + x])
+        } else {
+            None
         }
     }
 
-    pub fn width(&self) -> usize {
-        return self.map[0].len();
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
     }
 
-    pub fn height(&self) -> usize {
-        return self.map.len();
+    pub fn as_mut_slice(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, f32> {
+        self.data.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, f32> {
+        self.data.iter_mut()
+    }
+
+    // (x, z, height) for every cell in storage order
+    pub fn cells(&self) -> impl Iterator<Item = (usize, usize, f32)> + '_ {
+        let width = self.width;
+        self.data
+            .iter()
+            .enumerate()
+            .map(move |(i, &h)| (i % width, i / width, h))
+    }
+
+    pub fn row(&self, z: usize) -> &[f32] {
+        &self.data[z * self.width..(z + 1) * self.width]
+    }
+
+    pub fn row_mut(&mut self, z: usize) -> &mut [f32] {
+        &mut self.data[z * self.width..(z + 1) * self.width]
+    }
+
+    pub fn rows(&self) -> std::slice::ChunksExact<'_, f32> {
+        self.data.chunks_exact(self.width.max(1))
+    }
+
+    pub fn rows_mut(&mut self) -> std::slice::ChunksExactMut<'_, f32> {
+        self.data.chunks_exact_mut(self.width.max(1))
+    }
+
+    pub fn column(&self, x: usize) -> impl Iterator<Item = f32> + '_ {
+        assert!(x < self.width, "Column {} out of range", x);
+        self.data.iter().skip(x).step_by(self.width).copied()
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = impl Iterator<Item = f32> + '_> + '_ {
+        (0..self.width).map(move |x| self.column(x))
+    }
+
+    pub fn filter(&self, filter: &Filter, border: BorderMode) -> anyhow::Result<HeightMap> {
+        Ok(HeightMap {
+            width: self.width,
+            height: self.height,
+            data: filter::convolve(&self.data, self.width, self.height, filter, border)?,
+        })
+    }
+
+    // fractal perlin heights with the exact gradient (d/dx, d/dz) of every cell,
+    // in height per cell and stored in the same order as the heights
+    pub fn generate_with_gradient(
+        params: perlin::FractalPerlinParams,
+    ) -> (HeightMap, Vec<[f32; 2]>) {
+        let sampler = perlin::FractalPerlinSampler::new(&params);
+        let (width, height) = (params.width, params.height);
+
+        let mut hmap = HeightMap::new(width, height);
+        let mut gradient = vec![[0.0; 2]; width * height];
+        if width > 0 {
+            hmap.as_mut_slice()
+                .par_chunks_mut(width)
+                .zip(gradient.par_chunks_mut(width))
+                .enumerate()
+                .for_each(|(z, (heights, gradients))| {
+                    for x in 0..width {
+                        // sampler x runs down the rows and y along them
+                        let (h, d) =
+                            sampler.sample_deriv(z as f32 / height as f32, x as f32 / width as f32);
+                        heights[x] = h;
+                        gradients[x] = [d[1] / width as f32, d[0] / height as f32];
+                    }
+                });
+        }
+
+        (hmap, gradient)
     }
 
+    // unit normal of a surface whose height changes by (d/dx, d/dz) per cell
+    pub fn normal_from_gradient([dx, dz]: [f32; 2]) -> [f32; 3] {
+        let normal = Vector3::new(-dx, 1.0, -dz).normalize();
+        [normal.x, normal.y, normal.z]
+    }
+
+    // steepest slope in radians for a gradient (d/dx, d/dz) per cell
+    pub fn slope_from_gradient([dx, dz]: [f32; 2]) -> f32 {
+        (dx * dx + dz * dz).sqrt().atan()
+    }
+
+    // mesh lit by finite difference normals, for maps without an exact gradient
     pub fn to_mesh(&self) -> HeightMapMesh {
-        let height = self.height();
-        let width = self.width();
+        self.mesh(|x, z| HeightMap::normal_from_gradient(self.gradient_at(x, z)))
+    }
+
+    // mesh lit by exact normals, e.g. from the gradient of generate_with_gradient
+    pub fn to_mesh_with_gradient(&self, gradient: &[[f32; 2]]) -> anyhow::Result<HeightMapMesh> {
+        anyhow::ensure!(
+            gradient.len() == self.data.len(),
+            "Expected {} gradients for the {}x{} heightmap, got {}",
+            self.data.len(),
+            self.width,
+            self.height,
+            gradient.len()
+        );
+
+        Ok(self.mesh(|x, z| HeightMap::normal_from_gradient(gradient[z * self.width + x])))
+    }
+
+    // finite difference gradient (d/dx, d/dz) per cell, central inside the map
+    // and one sided along its edges
+    fn gradient_at(&self, x: usize, z: usize) -> [f32; 2] {
+        let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.width - 1));
+        let (z0, z1) = (z.saturating_sub(1), (z + 1).min(self.height - 1));
+        [
+            (self[(x1, z)] - self[(x0, z)]) / (x1 - x0).max(1) as f32,
+            (self[(x, z1)] - self[(x, z0)]) / (z1 - z0).max(1) as f32,
+        ]
+    }
+
+    fn mesh(&self, normal: impl Fn(usize, usize) -> [f32; 3]) -> HeightMapMesh {
+        let (width, height) = (self.width, self.height);
 
         let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(width * height);
         let mut normals: Vec<[f32; 3]> = Vec::with_capacity(width * height);
         let mut vertices: Vec<[f32; 3]> = Vec::with_capacity(width * height);
         let mut indices: Vec<u32> = Vec::new();
 
-        for x in 0..height {
-            for y in 0..width {
+        for z in 0..height {
+            for x in 0..width {
+                let h = self[(x, z)];
+
                 // UVs
-                uvs.push([x as f32 / width as f32, y as f32 / height as f32]);
+                uvs.push([x as f32 / width as f32, z as f32 / height as f32]);
 
                 // Normals
-                let height_l = if x > 0 {
-                    self.map[x - 1][y]
-                } else {
-                    self.map[x][y]
-                };
-
-                let height_r = if x < width - 1 {
-                    self.map[x + 1][y]
-                } else {
-                    self.map[x][y]
-                };
-
-                let height_d = if y > 0 {
-                    self.map[x][y - 1]
-                } else {
-                    self.map[x][y]
-                };
-
-                let height_u = if y < height - 1 {
-                    self.map[x][y + 1]
-                } else {
-                    self.map[x][y]
-                };
-
-                let normal =
-                    Vector3::new(height_l - height_r, NORMAL_Y_COMPONENT, height_d - height_u)
-                        .normalize();
-                normals.push([normal.x as f32, normal.y as f32, normal.z as f32]);
+                normals.push(normal(x, z));
 
                 // Indices
-                if x < (height - 1) && y < (width - 1) {
-                    let top_left = (x + y * width) as u32;
-                    let top_right = ((x + 1) + y * width) as u32;
-                    let bottom_left = (x + (y + 1) * width) as u32;
-                    let bottom_right = ((x + 1) + (y + 1) * width) as u32;
+                if x < (width - 1) && z < (height - 1) {
+                    let top_left = (x + z * width) as u32;
+                    let top_right = ((x + 1) + z * width) as u32;
+                    let bottom_left = (x + (z + 1) * width) as u32;
+                    let bottom_right = ((x + 1) + (z + 1) * width) as u32;
 
                     indices.push(top_left);
                     indices.push(bottom_left);
@@ -118,7 +312,7 @@ impl HeightMap {
                 }
 
                 // Vertices
-                vertices.push([x as f32, self.map[x][y] as f32, y as f32]);
+                vertices.push([x as f32, h, z as f32]);
             }
         }
 
@@ -131,51 +325,186 @@ impl HeightMap {
     }
 }
 
-impl Add<HeightMap> for HeightMap {
-    type Output = HeightMap;
+impl Index<(usize, usize)> for HeightMap {
+    type Output = f32;
+
+    fn index(&self, (x, z): (usize, usize)) -> &f32 {
+        assert!(
+            x < self.width && z < self.height,
+            "({}, {}) is outside the {}x{} heightmap",
+            x,
+            z,
+            self.width,
+            self.height
+        );
+        &self.data[z * self.width + x]
+    }
+}
+
+impl IndexMut<(usize, usize)> for HeightMap {
+    fn index_mut(&mut self, (x, z): (usize, usize)) -> &mut f32 {
+        assert!(
+            x < self.width && z < self.height,
+            "({}, {}) is outside the {}x{} heightmap",
+            x,
+            z,
+            self.width,
+            self.height
+        );
+        &mut self.data[z * self.width + x]
+    }
+}
 
-    fn add(self, other: HeightMap) -> HeightMap {
-        assert!(self.height() == other.height());
-        assert!(self.width() == other.width());
-        let mut _h = HeightMap::new(self.width(), self.height());
+impl<'a> IntoIterator for &'a HeightMap {
+    type Item = &'a f32;
+    type IntoIter = std::slice::Iter<'a, f32>;
 
-        for y in 0..self.height() {
-            for x in 0..self.width() {
-                _h.map[y][x] = self.map[y][x] + other.map[y][x];
-            }
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut HeightMap {
+    type Item = &'a mut f32;
+    type IntoIter = std::slice::IterMut<'a, f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.iter_mut()
+    }
+}
+
+impl TryFrom<TerrainHeights> for HeightMap {
+    type Error = anyhow::Error;
+
+    fn try_from(heights: TerrainHeights) -> anyhow::Result<HeightMap> {
+        HeightMap::from_vec(
+            heights.width as usize,
+            heights.height as usize,
+            heights.heights,
+        )
+    }
+}
+
+impl From<HeightMap> for TerrainHeights {
+    fn from(map: HeightMap) -> TerrainHeights {
+        TerrainHeights {
+            width: map.width as u32,
+            height: map.height as u32,
+            heights: map.data,
         }
+    }
+}
 
-        return _h;
+// coordinates of row i and column j, running 0..1 across the map
+fn sample_coords(
+    i: usize,
+    j: usize,
+    width: usize,
+    height: usize,
+    warp: Option<&DomainWarp>,
+) -> (f32, f32) {
+    let (x, y) = (i as f32 / height as f32, j as f32 / width as f32);
+    match warp {
+        Some(warp) => warp.apply(x, y),
+        None => (x, y),
     }
 }
 
 fn generate_fractal_perlin(params: perlin::FractalPerlinParams) -> HeightMap {
-    // let mut rng = rand::thread_rng();
-    // let seed = rng.gen::<u32>();
+    let sampler = perlin::FractalPerlinSampler::new(&params);
+
+    let mut hmap = HeightMap::new(params.width, params.height);
+    if params.width > 0 {
+        hmap.as_mut_slice()
+            .par_chunks_mut(params.width)
+            .enumerate()
+            .for_each(|(i, row)| sampler.fill_row(i, row));
+    }
+
+    hmap
+}
+
+fn generate_gradient_frac_perlin(params: perlin::GradientFractalPerlinParams) -> HeightMap {
     let permutation = perlin::generate_permutation(params.seed);
+    let warp = params.warp.as_ref().map(DomainWarp::new);
 
     let mut hmap = HeightMap::new(params.width, params.height);
     for i in 0..params.height {
         for j in 0..params.width {
-            hmap.map[i][j] = perlin::octave_perlin3d(
-                i as f32 / params.height as f32,
-                j as f32 / params.width as f32,
-                0.0,
+            let (x, y) = sample_coords(i, j, params.width, params.height, warp.as_ref());
+            hmap[(j, i)] = perlin::gradient_octave_perlin2d(
+                x * params.frequency,
+                y * params.frequency,
                 params.octaves,
                 params.persistence,
+                params.lacunarity,
+                params.sharpness,
                 &permutation,
-            ) as f32
-                * params.scale;
+            ) * params.scale;
         }
     }
 
-    return hmap;
+    hmap
 }
 
-fn generate_gradient_frac_perlin(params: perlin::GradientFractalPerlinParams) -> HeightMap {
-    HeightMap::new(10, 10)
+fn generate_simplex(params: simplex::SimplexParams) -> HeightMap {
+    let permutation = perlin::generate_permutation(params.seed);
+    let warp = params.warp.as_ref().map(DomainWarp::new);
+
+    let mut hmap = HeightMap::new(params.width, params.height);
+    for i in 0..params.height {
+        for j in 0..params.width {
+            let (x, y) = sample_coords(i, j, params.width, params.height, warp.as_ref());
+            let (x, y) = (x * params.frequency, y * params.frequency);
+            let value = match params.z {
+                Some(z) => simplex::octave_simplex3d(
+                    x,
+                    y,
+                    z,
+                    params.octaves,
+                    params.persistence,
+                    params.lacunarity,
+                    &permutation,
+                ),
+                None => simplex::octave_simplex2d(
+                    x,
+                    y,
+                    params.octaves,
+                    params.persistence,
+                    params.lacunarity,
+                    &permutation,
+                ),
+            };
+            hmap[(j, i)] = value * params.scale;
+        }
+    }
+
+    hmap
+}
+
+fn generate_worley(params: worley::WorleyParams) -> HeightMap {
+    let warp = params.warp.as_ref().map(DomainWarp::new);
+
+    let mut hmap = HeightMap::new(params.width, params.height);
+    for i in 0..params.height {
+        for j in 0..params.width {
+            let (x, y) = sample_coords(i, j, params.width, params.height, warp.as_ref());
+            let (x, y) = (x * params.frequency, y * params.frequency);
+            let distances = match params.z {
+                Some(z) => worley::worley3d(x, y, z, params.jitter, params.metric, params.seed),
+                None => worley::worley2d(x, y, params.jitter, params.metric, params.seed),
+            };
+            hmap[(j, i)] = params.feature.select(distances) * params.scale;
+        }
+    }
+
+    hmap
 }
 
 fn generate_diff_lim_agg(params: dla::DiffusionLimitedAggregationParams) -> HeightMap {
-    HeightMap::new(10, 10)
+    let (width, height) = params.output_size();
+    let hmap = dla::generate(params);
+    debug_assert!(hmap.width() == width && hmap.height() == height);
+
+    hmap
 }
@@ -1,3 +1,10 @@
 pub mod dla;
+pub mod filter;
+pub mod io;
 pub mod lib;
+pub mod ops;
 pub mod perlin;
+pub mod pipeline;
+pub mod simplex;
+pub mod warp;
+pub mod worley;
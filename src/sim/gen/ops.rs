@@ -0,0 +1,283 @@
+use std::ops::{Add, Mul, Sub};
+
+use anyhow::{Result, ensure};
+use serde::Serialize;
+
+use super::lib::HeightMap;
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct HeightMapStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+fn ensure_same_size(a: &HeightMap, b: &HeightMap) -> Result<()> {
+    ensure!(
+        a.width() == b.width() && a.height() == b.height(),
+        "Heightmap sizes differ, {}x{} and {}x{}",
+        a.width(),
+        a.height(),
+        b.width(),
+        b.height()
+    );
+    Ok(())
+}
+
+fn ensure_range(lo: f32, hi: f32) -> Result<()> {
+    ensure!(
+        lo.is_finite() && hi.is_finite() && lo <= hi,
+        "Invalid range {}..{}",
+        lo,
+        hi
+    );
+    Ok(())
+}
+
+impl HeightMap {
+    fn map_values(&self, f: impl Fn(f32) -> f32) -> HeightMap {
+        let mut out = self.clone();
+        for h in out.iter_mut() {
+            *h = f(*h);
+        }
+        out
+    }
+
+    fn zip_with(&self, other: &HeightMap, f: impl Fn(f32, f32) -> f32) -> Result<HeightMap> {
+        ensure_same_size(self, other)?;
+        let mut out = self.clone();
+        for (a, &b) in out.iter_mut().zip(other) {
+            *a = f(*a, b);
+        }
+        Ok(out)
+    }
+
+    // lowest and highest height, None for an empty map
+    pub fn bounds(&self) -> Option<(f32, f32)> {
+        self.iter().fold(None, |acc, &h| match acc {
+            None => Some((h, h)),
+            Some((lo, hi)) => Some((lo.min(h), hi.max(h))),
+        })
+    }
+
+    // None for an empty map
+    pub fn stats(&self) -> Option<HeightMapStats> {
+        let (min, max) = self.bounds()?;
+        let n = self.len() as f64;
+        let mean = self.iter().map(|&h| h as f64).sum::<f64>() / n;
+        let variance = self.iter().map(|&h| (h as f64 - mean).powi(2)).sum::<f64>() / n;
+
+        Some(HeightMapStats {
+            min,
+            max,
+            mean: mean as f32,
+            std_dev: variance.sqrt() as f32,
+        })
+    }
+
+    pub fn scaled(&self, factor: f32) -> HeightMap {
+        self.map_values(|h| h * factor)
+    }
+
+    pub fn offset(&self, amount: f32) -> HeightMap {
+        self.map_values(|h| h + amount)
+    }
+
+    pub fn min(&self, other: &HeightMap) -> Result<HeightMap> {
+        self.zip_with(other, f32::min)
+    }
+
+    pub fn max(&self, other: &HeightMap) -> Result<HeightMap> {
+        self.zip_with(other, f32::max)
+    }
+
+    // mask 0 keeps self, 1 takes other, values outside 0..1 are clamped
+    pub fn blend(&self, other: &HeightMap, mask: &HeightMap) -> Result<HeightMap> {
+        ensure_same_size(self, mask)?;
+        let mut out = self.zip_with(other, |a, b| b - a)?;
+        for ((d, &a), &m) in out.iter_mut().zip(self).zip(mask) {
+            *d = a + *d * m.clamp(0.0, 1.0);
+        }
+        Ok(out)
+    }
+
+    pub fn clamp(&self, lo: f32, hi: f32) -> Result<HeightMap> {
+        ensure_range(lo, hi)?;
+        Ok(self.map_values(|h| h.clamp(lo, hi)))
+    }
+
+    // linearly maps from onto to, heights outside from are extrapolated
+    pub fn remap(&self, from: (f32, f32), to: (f32, f32)) -> Result<HeightMap> {
+        ensure!(
+            from.0.is_finite() && from.1.is_finite() && from.0 != from.1,
+            "Cannot remap from the empty range {}..{}",
+            from.0,
+            from.1
+        );
+        ensure!(
+            to.0.is_finite() && to.1.is_finite(),
+            "Invalid target range {}..{}",
+            to.0,
+            to.1
+        );
+
+        let scale = (to.1 - to.0) / (from.1 - from.0);
+        Ok(self.map_values(|h| to.0 + (h - from.0) * scale))
+    }
+
+    // stretches the map to span lo..hi, a flat map becomes lo
+    pub fn normalize(&self, lo: f32, hi: f32) -> Result<HeightMap> {
+        ensure_range(lo, hi)?;
+        match self.bounds() {
+            Some((min, max)) if max > min => self.remap((min, max), (lo, hi)),
+            _ => Ok(self.map_values(|_| lo)),
+        }
+    }
+
+    // raises the heights within the map's own range to exponent, above 1 flattens
+    // lowlands and sharpens peaks, below 1 does the opposite
+    pub fn power(&self, exponent: f32) -> Result<HeightMap> {
+        ensure!(
+            exponent.is_finite() && exponent > 0.0,
+            "Power curve exponent must be positive, got {}",
+            exponent
+        );
+
+        let Some((min, max)) = self.bounds().filter(|(min, max)| max > min) else {
+            return Ok(self.clone());
+        };
+        let range = max - min;
+        Ok(self.map_values(|h| min + ((h - min) / range).powf(exponent) * range))
+    }
+
+    // splits the map's range into levels bands, sharpness 1 leaves the slopes
+    // unchanged and higher values flatten each band into a step with a steep riser
+    pub fn terrace(&self, levels: u32, sharpness: f32) -> Result<HeightMap> {
+        ensure!(levels > 0, "Terrace needs at least one level");
+        ensure!(
+            sharpness.is_finite() && sharpness >= 1.0,
+            "Terrace sharpness must be at least 1, got {}",
+            sharpness
+        );
+
+        let Some((min, max)) = self.bounds().filter(|(min, max)| max > min) else {
+            return Ok(self.clone());
+        };
+        let step = (max - min) / levels as f32;
+        Ok(self.map_values(|h| {
+            let t = ((h - min) / step).min(levels as f32);
+            let band = t.floor().min(levels as f32 - 1.0);
+            min + (band + (t - band).powf(sharpness)) * step
+        }))
+    }
+
+    // bilinear resample, the corner cells of both maps line up
+    pub fn resample(&self, width: usize, height: usize) -> Result<HeightMap> {
+        ensure!(
+            width > 0 && height > 0,
+            "Cannot resample to {}x{}",
+            width,
+            height
+        );
+        ensure!(!self.is_empty(), "Cannot resample an empty heightmap");
+
+        let step = |from: usize, to: usize| {
+            if to > 1 {
+                (from - 1) as f32 / (to - 1) as f32
+            } else {
+                0.0
+            }
+        };
+        let (step_x, step_z) = (step(self.width(), width), step(self.height(), height));
+
+        let mut out = HeightMap::new(width, height);
+        for z in 0..height {
+            let sz = z as f32 * step_z;
+            let z0 = (sz.floor() as usize).min(self.height() - 1);
+            let z1 = (z0 + 1).min(self.height() - 1);
+            let fz = sz - z0 as f32;
+
+            for x in 0..width {
+                let sx = x as f32 * step_x;
+                let x0 = (sx.floor() as usize).min(self.width() - 1);
+                let x1 = (x0 + 1).min(self.width() - 1);
+                let fx = sx - x0 as f32;
+
+                let top = self[(x0, z0)] * (1.0 - fx) + self[(x1, z0)] * fx;
+                let bottom = self[(x0, z1)] * (1.0 - fx) + self[(x1, z1)] * fx;
+                out[(x, z)] = top * (1.0 - fz) + bottom * fz;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+impl Add<&HeightMap> for &HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn add(self, other: &HeightMap) -> Result<HeightMap> {
+        self.zip_with(other, |a, b| a + b)
+    }
+}
+
+impl Add<HeightMap> for HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn add(self, other: HeightMap) -> Result<HeightMap> {
+        &self + &other
+    }
+}
+
+impl Sub<&HeightMap> for &HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn sub(self, other: &HeightMap) -> Result<HeightMap> {
+        self.zip_with(other, |a, b| a - b)
+    }
+}
+
+impl Sub<HeightMap> for HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn sub(self, other: HeightMap) -> Result<HeightMap> {
+        &self - &other
+    }
+}
+
+// multiplying by another map applies it as a mask
+impl Mul<&HeightMap> for &HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn mul(self, mask: &HeightMap) -> Result<HeightMap> {
+        self.zip_with(mask, |a, m| a * m)
+    }
+}
+
+impl Mul<HeightMap> for HeightMap {
+    type Output = Result<HeightMap>;
+
+    fn mul(self, mask: HeightMap) -> Result<HeightMap> {
+        &self * &mask
+    }
+}
+
+impl Mul<f32> for &HeightMap {
+    type Output = HeightMap;
+
+    fn mul(self, factor: f32) -> HeightMap {
+        self.scaled(factor)
+    }
+}
+
+impl Mul<f32> for HeightMap {
+    type Output = HeightMap;
+
+    fn mul(mut self, factor: f32) -> HeightMap {
+        for h in self.iter_mut() {
+            *h *= factor;
+        }
+        self
+    }
+}
@@ -1,22 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+use super::warp::{DomainWarp, DomainWarpParams};
+
 const GRAD2D: [[i32; 2]; 4] = [[0, 1], [0, -1], [1, 0], [-1, 0]];
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct FractalPerlinParams {
     pub height: usize,
     pub width: usize,
     pub scale: f32,
     pub octaves: i32,
     pub persistence: f32,
+    pub lacunarity: f32, // frequency multiplier between octaves
+    pub fractal: Fractal,
+    pub seed: u32,
+    pub tileable: bool, // wraps seamlessly on both axes, octave frequencies are rounded
+    pub warp: Option<DomainWarpParams>,
+}
+
+// how octaves are combined. offset lifts the noise before it is weighted, gain
+// controls how much a ridge suppresses detail in the octaves above it and
+// sharpness is the exponent applied to each ridge
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fractal {
+    #[default]
+    Fbm,
+    Billow,
+    Ridged {
+        offset: f32,
+        gain: f32,
+        sharpness: f32,
+    },
+    HybridMultifractal {
+        offset: f32,
+    },
+    HeterogeneousTerrain {
+        offset: f32,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GradientFractalPerlinParams {
+    pub height: usize,
+    pub width: usize,
+    pub scale: f32,
+    pub frequency: f32, // lattice cells across the map at the first octave
+    pub octaves: i32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+    pub sharpness: f32, // how strongly accumulated slope damps later octaves
     pub seed: u32,
+    pub warp: Option<DomainWarpParams>,
 }
 
-#[derive(Clone)]
-pub struct GradientFractalPerlinParams {}
+impl Default for FractalPerlinParams {
+    fn default() -> Self {
+        FractalPerlinParams {
+            height: 256,
+            width: 256,
+            scale: 40.0,
+            octaves: 8,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            fractal: Fractal::Fbm,
+            seed: 0,
+            tileable: false,
+            warp: None,
+        }
+    }
+}
+
+impl Default for GradientFractalPerlinParams {
+    fn default() -> Self {
+        GradientFractalPerlinParams {
+            height: 256,
+            width: 256,
+            scale: 40.0,
+            frequency: 4.0,
+            octaves: 8,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            sharpness: 1.0,
+            seed: 0,
+            warp: None,
+        }
+    }
+}
+
+// rotates each octave so lattice artifacts do not line up
+const OCTAVE_ROTATION: [[f32; 2]; 2] = [[0.8, -0.6], [0.6, 0.8]];
 
 fn fade(t: f32) -> f32 {
     return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
 }
 
+fn fade_derivative(t: f32) -> f32 {
+    30.0 * t * t * (t * (t - 2.0) + 1.0)
+}
+
 fn lerp(a: f32, b: f32, t: f32) -> f32 {
     (a + t * (b - a)).into()
 }
@@ -60,6 +144,26 @@ fn grad3d(hash: i32, x: f32, y: f32, z: f32) -> f32 {
 //     return if (h & 1) == 0 { u } else { -u } + if (h & 2) == 0 { v } else { -v };
 // }
 
+// gradient vector picked by grad3d, so that grad3d(h, x, y, z) == dot(g, (x, y, z))
+fn grad3d_vector(hash: i32) -> [f32; 3] {
+    match hash & 0xF {
+        0x0 | 0xC => [1.0, 1.0, 0.0],
+        0x1 => [-1.0, 1.0, 0.0],
+        0x2 => [1.0, -1.0, 0.0],
+        0x3 => [-1.0, -1.0, 0.0],
+        0x4 => [1.0, 0.0, 1.0],
+        0x5 => [-1.0, 0.0, 1.0],
+        0x6 => [1.0, 0.0, -1.0],
+        0x7 => [-1.0, 0.0, -1.0],
+        0x8 => [0.0, 1.0, 1.0],
+        0x9 | 0xD => [0.0, -1.0, 1.0],
+        0xA => [0.0, 1.0, -1.0],
+        0xB | 0xF => [0.0, -1.0, -1.0],
+        0xE => [-1.0, 1.0, 0.0],
+        _ => [0.0, 0.0, 0.0], // should never happen
+    }
+}
+
 fn grad2d(hash: i32, x: f32, y: f32) -> f32 {
     let index = hash % 4;
     let g = GRAD2D[index as usize];
@@ -67,10 +171,58 @@ fn grad2d(hash: i32, x: f32, y: f32) -> f32 {
 }
 
 pub fn perlin3d(x: f32, y: f32, z: f32, p: &Vec<i32>) -> f32 {
-    let _x = x.floor() as usize & 255;
-    let _y = y.floor() as usize & 255;
-    let _z = z.floor() as usize & 255;
+    let _x = lattice(x);
+    let _y = lattice(y);
+    let _z = lattice(z);
+
+    perlin3d_cell(x, y, z, [_x, _x + 1], [_y, _y + 1], [_z, _z + 1], p)
+}
+
+// repeats every period lattice cells along each axis, period 256 matches perlin3d
+pub fn perlin3d_periodic(x: f32, y: f32, z: f32, period: [usize; 3], p: &[i32]) -> f32 {
+    let [cx, cy, cz] = [
+        wrap_lattice(x, period[0], p),
+        wrap_lattice(y, period[1], p),
+        wrap_lattice(z, period[2], p),
+    ];
+
+    perlin3d_cell(x, y, z, cx, cy, cz, p)
+}
+
+// lattice index of the cell containing t. The floor is wrapped as a signed value
+// so cells left of 0 repeat the table instead of all collapsing onto cell 0
+fn lattice(t: f32) -> usize {
+    (t.floor() as i32 & 255) as usize
+}
+
+// lattice index of the cell containing t and of the next one, wrapped by period
+fn wrap_lattice(t: f32, period: usize, p: &[i32]) -> [usize; 2] {
+    let period = period.max(1) as i64;
+    let i = t.floor() as i64;
+    [
+        fold_lattice(i.rem_euclid(period) as usize, p),
+        fold_lattice((i + 1).rem_euclid(period) as usize, p),
+    ]
+}
+
+// maps a wrapped cell onto the 256 entry table. Cells in the first 256 map to
+// themselves, so period 256 still matches the plain functions, later blocks of
+// 256 are shuffled by which block they are in so periods up to 65536 do not
+// repeat inside themselves
+fn fold_lattice(i: usize, p: &[i32]) -> usize {
+    (i & 255) ^ (p[(i >> 8) & 255] ^ p[0]) as usize
+}
 
+// noise inside one lattice cell, given the indices of its corners
+fn perlin3d_cell(
+    x: f32,
+    y: f32,
+    z: f32,
+    [x0, x1]: [usize; 2],
+    [y0, y1]: [usize; 2],
+    [z0, z1]: [usize; 2],
+    p: &[i32],
+) -> f32 {
     let xf: f32 = x - x.floor() as f32;
     let yf: f32 = y - y.floor() as f32;
     let zf: f32 = z - z.floor() as f32;
@@ -79,14 +231,14 @@ pub fn perlin3d(x: f32, y: f32, z: f32, p: &Vec<i32>) -> f32 {
     let v = fade(yf);
     let w = fade(zf);
 
-    let aaa = p[p[p[_x] as usize + _y] as usize + _z];
-    let aba = p[p[p[_x] as usize + _y + 1] as usize + _z];
-    let aab = p[p[p[_x] as usize + _y] as usize + _z + 1];
-    let abb = p[p[p[_x] as usize + _y + 1] as usize + _z + 1];
-    let baa = p[p[p[_x + 1] as usize + _y] as usize + _z];
-    let bba = p[p[p[_x + 1] as usize + _y + 1] as usize + _z];
-    let bab = p[p[p[_x + 1] as usize + _y] as usize + _z + 1];
-    let bbb = p[p[p[_x + 1] as usize + _y + 1] as usize + _z + 1];
+    let aaa = p[p[p[x0] as usize + y0] as usize + z0];
+    let aba = p[p[p[x0] as usize + y1] as usize + z0];
+    let aab = p[p[p[x0] as usize + y0] as usize + z1];
+    let abb = p[p[p[x0] as usize + y1] as usize + z1];
+    let baa = p[p[p[x1] as usize + y0] as usize + z0];
+    let bba = p[p[p[x1] as usize + y1] as usize + z0];
+    let bab = p[p[p[x1] as usize + y0] as usize + z1];
+    let bbb = p[p[p[x1] as usize + y1] as usize + z1];
 
     let x01 = lerp(
         grad3d(aaa, xf, yf, zf) as f32,
@@ -115,26 +267,335 @@ pub fn perlin3d(x: f32, y: f32, z: f32, p: &Vec<i32>) -> f32 {
     return lerp(y1, y2, w);
 }
 
+// lanes evaluated together by perlin3d_batch, wide enough for avx
+const BATCH_LANES: usize = 8;
+
+// grad3d as (first axis, sign, second axis, sign), so a lookup can replace its
+// match while keeping the same operations
+const GRAD3D_TERMS: [(usize, f32, usize, f32); 16] = [
+    (0, 1.0, 1, 1.0),
+    (0, -1.0, 1, 1.0),
+    (0, 1.0, 1, -1.0),
+    (0, -1.0, 1, -1.0),
+    (0, 1.0, 2, 1.0),
+    (0, -1.0, 2, 1.0),
+    (0, 1.0, 2, -1.0),
+    (0, -1.0, 2, -1.0),
+    (1, 1.0, 2, 1.0),
+    (1, -1.0, 2, 1.0),
+    (1, 1.0, 2, -1.0),
+    (1, -1.0, 2, -1.0),
+    (1, 1.0, 0, 1.0),
+    (1, -1.0, 2, 1.0),
+    (1, 1.0, 0, -1.0),
+    (1, -1.0, 2, -1.0),
+];
+
+// perlin3d for every (xs[k], ys[k], zs[k]), or perlin3d_periodic when period is
+// set. Results are identical to the scalar functions
+pub fn perlin3d_batch(
+    xs: &[f32],
+    ys: &[f32],
+    zs: &[f32],
+    period: Option<[usize; 3]>,
+    p: &[i32],
+    out: &mut [f32],
+) {
+    assert!(
+        xs.len() == out.len() && ys.len() == out.len() && zs.len() == out.len(),
+        "Batch coordinates and output differ in length"
+    );
+
+    let chunks = xs
+        .chunks(BATCH_LANES)
+        .zip(ys.chunks(BATCH_LANES))
+        .zip(zs.chunks(BATCH_LANES))
+        .zip(out.chunks_mut(BATCH_LANES));
+    for (((xs, ys), zs), out) in chunks {
+        perlin3d_lanes([xs, ys, zs], period, p, out);
+    }
+}
+
+// the lattice lookups are gathered lane by lane, everything else runs over
+// whole lanes so it compiles to vector instructions
+fn perlin3d_lanes(coords: [&[f32]; 3], period: Option<[usize; 3]>, p: &[i32], out: &mut [f32]) {
+    let n = out.len();
+
+    let mut pos = [[0.0f32; BATCH_LANES]; 3];
+    for (axis, c) in coords.iter().enumerate() {
+        pos[axis][..n].copy_from_slice(c);
+    }
+
+    let mut frac = [[0.0f32; BATCH_LANES]; 3];
+    let mut faded = [[0.0f32; BATCH_LANES]; 3];
+    for axis in 0..3 {
+        for k in 0..BATCH_LANES {
+            let t = pos[axis][k];
+            frac[axis][k] = t - t.floor();
+            faded[axis][k] = fade(frac[axis][k]);
+        }
+    }
+
+    // corner c is offset by (c & 1, c >> 1 & 1, c >> 2) from the cell origin
+    let mut hashes = [[0i32; BATCH_LANES]; 8];
+    for k in 0..n {
+        let [cx, cy, cz] = [0, 1, 2].map(|axis| {
+            let t = pos[axis][k];
+            match period {
+                Some(period) => wrap_lattice(t, period[axis], p),
+                None => {
+                    let i = lattice(t);
+                    [i, i + 1]
+                }
+            }
+        });
+        for (c, hash) in hashes.iter_mut().enumerate() {
+            hash[k] = p[p[p[cx[c & 1]] as usize + cy[c >> 1 & 1]] as usize + cz[c >> 2]];
+        }
+    }
+
+    let mut dots = [[0.0f32; BATCH_LANES]; 8];
+    for (c, dot) in dots.iter_mut().enumerate() {
+        let offset = [(c & 1) as f32, (c >> 1 & 1) as f32, (c >> 2) as f32];
+        for k in 0..BATCH_LANES {
+            let corner = [
+                frac[0][k] - offset[0],
+                frac[1][k] - offset[1],
+                frac[2][k] - offset[2],
+            ];
+            let (a, sa, b, sb) = GRAD3D_TERMS[(hashes[c][k] & 0xF) as usize];
+            dot[k] = sa * corner[a] + sb * corner[b];
+        }
+    }
+
+    let mut values = [0.0f32; BATCH_LANES];
+    for k in 0..BATCH_LANES {
+        let [u, v, w] = [faded[0][k], faded[1][k], faded[2][k]];
+        let y1 = lerp(
+            lerp(dots[0][k], dots[1][k], u),
+            lerp(dots[2][k], dots[3][k], u),
+            v,
+        );
+        let y2 = lerp(
+            lerp(dots[4][k], dots[5][k], u),
+            lerp(dots[6][k], dots[7][k], u),
+            v,
+        );
+        values[k] = lerp(y1, y2, w);
+    }
+    out.copy_from_slice(&values[..n]);
+}
+
 pub fn perlin2d(x: f32, y: f32, p: &Vec<i32>) -> f32 {
-    let _x = x.floor() as usize & 255;
-    let _y = y.floor() as usize & 255;
+    let _x = lattice(x);
+    let _y = lattice(y);
+
+    perlin2d_cell(x, y, [_x, _x + 1], [_y, _y + 1], p)
+}
+
+// repeats every period lattice cells along each axis, period 256 matches perlin2d
+pub fn perlin2d_periodic(x: f32, y: f32, period: [usize; 2], p: &[i32]) -> f32 {
+    let cx = wrap_lattice(x, period[0], p);
+    let cy = wrap_lattice(y, period[1], p);
+
+    perlin2d_cell(x, y, cx, cy, p)
+}
 
+fn perlin2d_cell(x: f32, y: f32, [x0, x1]: [usize; 2], [y0, y1]: [usize; 2], p: &[i32]) -> f32 {
     let xf = x - x.floor() as f32;
     let yf = y - y.floor() as f32;
 
     let u = fade(xf);
     let v = fade(yf);
 
-    let aa = grad2d(p[p[_x] as usize + _y], xf, yf);
-    let ab = grad2d(p[p[_x] as usize + _y + 1], xf, yf - 1.0);
-    let bb = grad2d(p[p[_x + 1] as usize + _y + 1], xf - 1.0, yf - 1.0);
-    let ba = grad2d(p[p[_x + 1] as usize + _y], xf - 1.0, yf);
+    let aa = grad2d(p[p[x0] as usize + y0], xf, yf);
+    let ab = grad2d(p[p[x0] as usize + y1], xf, yf - 1.0);
+    let bb = grad2d(p[p[x1] as usize + y1], xf - 1.0, yf - 1.0);
+    let ba = grad2d(p[p[x1] as usize + y0], xf - 1.0, yf);
 
     let x1 = lerp(aa, ba, u);
     let x2 = lerp(ab, bb, u);
     return lerp(x1, x2, v);
 }
 
+// returns the noise value and its partial derivatives (d/dx, d/dy)
+pub fn perlin2d_deriv(x: f32, y: f32, p: &[i32]) -> (f32, [f32; 2]) {
+    let _x = lattice(x);
+    let _y = lattice(y);
+
+    let xf = x - x.floor();
+    let yf = y - y.floor();
+
+    let u = fade(xf);
+    let v = fade(yf);
+    let du = fade_derivative(xf);
+    let dv = fade_derivative(yf);
+
+    let ga = GRAD2D[(p[p[_x] as usize + _y] % 4) as usize];
+    let gb = GRAD2D[(p[p[_x + 1] as usize + _y] % 4) as usize];
+    let gc = GRAD2D[(p[p[_x] as usize + _y + 1] % 4) as usize];
+    let gd = GRAD2D[(p[p[_x + 1] as usize + _y + 1] % 4) as usize];
+    let [ga, gb, gc, gd] = [ga, gb, gc, gd].map(|g| [g[0] as f32, g[1] as f32]);
+
+    let va = ga[0] * xf + ga[1] * yf;
+    let vb = gb[0] * (xf - 1.0) + gb[1] * yf;
+    let vc = gc[0] * xf + gc[1] * (yf - 1.0);
+    let vd = gd[0] * (xf - 1.0) + gd[1] * (yf - 1.0);
+
+    // n = va + u(vb - va) + v(vc - va) + uv(va - vb - vc + vd)
+    let k = va - vb - vc + vd;
+    let value = va + u * (vb - va) + v * (vc - va) + u * v * k;
+
+    let mut d = [0.0; 2];
+    for i in 0..2 {
+        d[i] = ga[i]
+            + u * (gb[i] - ga[i])
+            + v * (gc[i] - ga[i])
+            + u * v * (ga[i] - gb[i] - gc[i] + gd[i]);
+    }
+    d[0] += du * (vb - va + v * k);
+    d[1] += dv * (vc - va + u * k);
+
+    (value, d)
+}
+
+// returns the noise value and its partial derivatives (d/dx, d/dy, d/dz)
+pub fn perlin3d_deriv(x: f32, y: f32, z: f32, p: &[i32]) -> (f32, [f32; 3]) {
+    let _x = lattice(x);
+    let _y = lattice(y);
+    let _z = lattice(z);
+
+    perlin3d_deriv_cell(x, y, z, [_x, _x + 1], [_y, _y + 1], [_z, _z + 1], p)
+}
+
+// perlin3d_deriv that repeats every period lattice cells along each axis
+pub fn perlin3d_periodic_deriv(
+    x: f32,
+    y: f32,
+    z: f32,
+    period: [usize; 3],
+    p: &[i32],
+) -> (f32, [f32; 3]) {
+    let [cx, cy, cz] = [
+        wrap_lattice(x, period[0], p),
+        wrap_lattice(y, period[1], p),
+        wrap_lattice(z, period[2], p),
+    ];
+
+    perlin3d_deriv_cell(x, y, z, cx, cy, cz, p)
+}
+
+fn perlin3d_deriv_cell(
+    x: f32,
+    y: f32,
+    z: f32,
+    [x0, x1]: [usize; 2],
+    [y0, y1]: [usize; 2],
+    [z0, z1]: [usize; 2],
+    p: &[i32],
+) -> (f32, [f32; 3]) {
+    let xf = x - x.floor();
+    let yf = y - y.floor();
+    let zf = z - z.floor();
+
+    let u = fade(xf);
+    let v = fade(yf);
+    let w = fade(zf);
+    let du = fade_derivative(xf);
+    let dv = fade_derivative(yf);
+    let dw = fade_derivative(zf);
+
+    // corners a..h are (000, 100, 010, 110, 001, 101, 011, 111)
+    let ga = grad3d_vector(p[p[p[x0] as usize + y0] as usize + z0]);
+    let gb = grad3d_vector(p[p[p[x1] as usize + y0] as usize + z0]);
+    let gc = grad3d_vector(p[p[p[x0] as usize + y1] as usize + z0]);
+    let gd = grad3d_vector(p[p[p[x1] as usize + y1] as usize + z0]);
+    let ge = grad3d_vector(p[p[p[x0] as usize + y0] as usize + z1]);
+    let gf = grad3d_vector(p[p[p[x1] as usize + y0] as usize + z1]);
+    let gg = grad3d_vector(p[p[p[x0] as usize + y1] as usize + z1]);
+    let gh = grad3d_vector(p[p[p[x1] as usize + y1] as usize + z1]);
+
+    let dot = |g: [f32; 3], x: f32, y: f32, z: f32| g[0] * x + g[1] * y + g[2] * z;
+    let va = dot(ga, xf, yf, zf);
+    let vb = dot(gb, xf - 1.0, yf, zf);
+    let vc = dot(gc, xf, yf - 1.0, zf);
+    let vd = dot(gd, xf - 1.0, yf - 1.0, zf);
+    let ve = dot(ge, xf, yf, zf - 1.0);
+    let vf = dot(gf, xf - 1.0, yf, zf - 1.0);
+    let vg = dot(gg, xf, yf - 1.0, zf - 1.0);
+    let vh = dot(gh, xf - 1.0, yf - 1.0, zf - 1.0);
+
+    // trilinear blend written out as a polynomial in u, v, w
+    let k1 = vb - va;
+    let k2 = vc - va;
+    let k3 = ve - va;
+    let k4 = va - vb - vc + vd;
+    let k5 = va - vc - ve + vg;
+    let k6 = va - vb - ve + vf;
+    let k7 = -va + vb + vc - vd + ve - vf - vg + vh;
+
+    let value =
+        va + u * k1 + v * k2 + w * k3 + u * v * k4 + v * w * k5 + w * u * k6 + u * v * w * k7;
+
+    let mut d = [0.0; 3];
+    for i in 0..3 {
+        d[i] = ga[i]
+            + u * (gb[i] - ga[i])
+            + v * (gc[i] - ga[i])
+            + w * (ge[i] - ga[i])
+            + u * v * (ga[i] - gb[i] - gc[i] + gd[i])
+            + v * w * (ga[i] - gc[i] - ge[i] + gg[i])
+            + w * u * (ga[i] - gb[i] - ge[i] + gf[i])
+            + u * v * w * (-ga[i] + gb[i] + gc[i] - gd[i] + ge[i] - gf[i] - gg[i] + gh[i]);
+    }
+    d[0] += du * (k1 + v * k4 + w * k6 + v * w * k7);
+    d[1] += dv * (k2 + w * k5 + u * k4 + w * u * k7);
+    d[2] += dw * (k3 + u * k6 + v * k5 + u * v * k7);
+
+    (value, d)
+}
+
+// fbm where each octave is damped by the slope accumulated so far, steep areas
+// stay smooth while flat areas pick up detail, which reads as eroded ridges
+pub fn gradient_octave_perlin2d(
+    x: f32,
+    y: f32,
+    octaves: i32,
+    persistence: f32,
+    lacunarity: f32,
+    sharpness: f32,
+    permutation: &[i32],
+) -> f32 {
+    let (mut px, mut py) = (x, y);
+    let mut value = 0.0;
+    let mut max_value = 0.0;
+    let mut amplitude = 1.0;
+    let mut dx = 0.0;
+    let mut dy = 0.0;
+
+    for _ in 0..octaves {
+        let (n, d) = perlin2d_deriv(px, py, permutation);
+        dx += d[0];
+        dy += d[1];
+
+        value += amplitude * n / (1.0 + sharpness * (dx * dx + dy * dy));
+        max_value += amplitude;
+        amplitude *= persistence;
+
+        let [[m00, m01], [m10, m11]] = OCTAVE_ROTATION;
+        (px, py) = (
+            (m00 * px + m01 * py) * lacunarity,
+            (m10 * px + m11 * py) * lacunarity,
+        );
+    }
+
+    if max_value > 0.0 {
+        value / max_value
+    } else {
+        0.0
+    }
+}
+
 pub fn octave_perlin3d(
     x: f32,
     y: f32,
@@ -177,6 +638,467 @@ pub fn octave_perlin2d(
     return value / max_value;
 }
 
+// fbm that repeats every period units of x, y and z. Each octave doubles the
+// frequency, so its lattice period doubles with it
+pub fn octave_perlin3d_periodic(
+    x: f32,
+    y: f32,
+    z: f32,
+    octaves: i32,
+    persistence: f32,
+    period: [usize; 3],
+    permutation: &[i32],
+) -> f32 {
+    let mut value = 0.0;
+    let mut max_value = 1.0;
+
+    for o in 0..octaves {
+        let f = 2.0f32.powi(o);
+        let amplitude = persistence.powi(o);
+        // periods past 2^16 are multiples of the 65536 cell folded lattice and wrap alike
+        let period = period.map(|p| p << o.min(16));
+
+        max_value += amplitude;
+        value += perlin3d_periodic(x * f, y * f, z * f, period, permutation) * amplitude;
+    }
+
+    value / max_value
+}
+
+pub fn octave_perlin2d_periodic(
+    x: f32,
+    y: f32,
+    octaves: i32,
+    persistence: f32,
+    period: [usize; 2],
+    permutation: &[i32],
+) -> f32 {
+    let mut value = 0.0;
+    let mut max_value = 1.0;
+
+    for o in 0..octaves {
+        let f = 2.0f32.powi(o);
+        let amplitude = persistence.powi(o);
+        // periods past 2^16 are multiples of the 65536 cell folded lattice and wrap alike
+        let period = period.map(|p| p << o.min(16));
+
+        max_value += amplitude;
+        value += perlin2d_periodic(x * f, y * f, period, permutation) * amplitude;
+    }
+
+    value / max_value
+}
+
+// octave_perlin3d and its partial derivatives (d/dx, d/dy, d/dz)
+pub fn octave_perlin3d_deriv(
+    x: f32,
+    y: f32,
+    z: f32,
+    octaves: i32,
+    persistence: f32,
+    permutation: &[i32],
+) -> (f32, [f32; 3]) {
+    let mut value = 0.0;
+    let mut d = [0.0; 3];
+    let mut max_value = 1.0;
+
+    for o in 0..octaves {
+        let f = 2.0f32.powi(o);
+        let amplitude = persistence.powi(o);
+
+        let (n, dn) = perlin3d_deriv(x * f, y * f, z * f, permutation);
+        max_value += amplitude;
+        value += n * amplitude;
+        for i in 0..3 {
+            d[i] += dn[i] * f * amplitude;
+        }
+    }
+
+    (value / max_value, d.map(|d| d / max_value))
+}
+
+// octave_perlin2d and its partial derivatives (d/dx, d/dy)
+pub fn octave_perlin2d_deriv(
+    x: f32,
+    y: f32,
+    octaves: i32,
+    persistence: f32,
+    permutation: &[i32],
+) -> (f32, [f32; 2]) {
+    let mut value = 0.0;
+    let mut d = [0.0; 2];
+    let mut max_value = 1.0;
+
+    for o in 0..octaves {
+        let f = 2.0f32.powi(o);
+        let amplitude = persistence.powi(o);
+
+        let (n, dn) = perlin2d_deriv(x * f, y * f, permutation);
+        max_value += amplitude;
+        value += n * amplitude;
+        for i in 0..2 {
+            d[i] += dn[i] * f * amplitude;
+        }
+    }
+
+    (value / max_value, d.map(|d| d / max_value))
+}
+
+// running total of one sample's octaves, shared by the scalar and batch paths so
+// both round identically
+#[derive(Copy, Clone)]
+struct FractalSum {
+    fractal: Fractal,
+    value: f32,
+    max_value: f32,
+    // ridges and hybrid multifractals scale each octave by the ones below it
+    weight: f32,
+}
+
+impl FractalSum {
+    fn new(fractal: Fractal) -> Self {
+        FractalSum {
+            fractal,
+            value: 0.0,
+            // plain fbm keeps its original normalisation, which also counts a unit
+            // amplitude that is never sampled
+            max_value: if fractal == Fractal::Fbm { 1.0 } else { 0.0 },
+            weight: 1.0,
+        }
+    }
+
+    fn add(&mut self, octave: i32, amplitude: f32, n: f32) {
+        match self.fractal {
+            Fractal::Fbm => self.value += n * amplitude,
+            Fractal::Billow => self.value += (2.0 * n.abs() - 1.0) * amplitude,
+            Fractal::Ridged {
+                offset,
+                gain,
+                sharpness,
+            } => {
+                let signal = (offset - n.abs()).max(0.0).powf(sharpness) * self.weight;
+                self.weight = (signal * gain).clamp(0.0, 1.0);
+                self.value += signal * amplitude;
+            }
+            Fractal::HybridMultifractal { offset } => {
+                let signal = (n + offset) * amplitude;
+                if octave == 0 {
+                    self.value = signal;
+                    self.weight = signal;
+                } else {
+                    self.weight = self.weight.min(1.0);
+                    self.value += self.weight * signal;
+                    self.weight *= signal;
+                }
+            }
+            Fractal::HeterogeneousTerrain { offset } => {
+                // higher octaves are scaled by the height so far, valleys stay smooth
+                if octave == 0 {
+                    self.value = n + offset;
+                } else {
+                    self.value += (n + offset) * amplitude * self.value;
+                }
+            }
+        }
+        self.max_value += amplitude;
+    }
+
+    fn finish(&self) -> f32 {
+        if self.max_value > 0.0 {
+            self.value / self.max_value
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Fractal {
+    // sums octaves of sample, which is called with each octave's frequency, e.g.
+    // |f| perlin3d(x * f, y * f, z * f, &permutation)
+    pub fn evaluate(
+        self,
+        octaves: i32,
+        persistence: f32,
+        lacunarity: f32,
+        sample: impl Fn(f32) -> f32,
+    ) -> f32 {
+        let mut sum = FractalSum::new(self);
+        for o in 0..octaves {
+            sum.add(o, persistence.powi(o), sample(lacunarity.powi(o)));
+        }
+
+        sum.finish()
+    }
+
+    // evaluate for a batch of samples, sample fills its slice with every
+    // sample's noise at the given frequency
+    pub fn evaluate_batch(
+        self,
+        octaves: i32,
+        persistence: f32,
+        lacunarity: f32,
+        mut sample: impl FnMut(f32, &mut [f32]),
+        out: &mut [f32],
+    ) {
+        let mut sums = vec![FractalSum::new(self); out.len()];
+        let mut noise = vec![0.0; out.len()];
+        for o in 0..octaves {
+            let amplitude = persistence.powi(o);
+            sample(lacunarity.powi(o), &mut noise);
+            for (sum, &n) in sums.iter_mut().zip(&noise) {
+                sum.add(o, amplitude, n);
+            }
+        }
+
+        for (o, sum) in out.iter_mut().zip(&sums) {
+            *o = sum.finish();
+        }
+    }
+
+    // evaluate along with the gradient of the result. sample returns each
+    // octave's noise and its gradient with respect to the sample position
+    pub fn evaluate_deriv<const N: usize>(
+        self,
+        octaves: i32,
+        persistence: f32,
+        lacunarity: f32,
+        sample: impl Fn(f32) -> (f32, [f32; N]),
+    ) -> (f32, [f32; N]) {
+        let mut value = 0.0;
+        let mut d = [0.0; N];
+        let mut max_value = if self == Fractal::Fbm { 1.0 } else { 0.0 };
+        let mut weight = 1.0f32;
+        let mut dweight = [0.0; N];
+
+        for o in 0..octaves {
+            let amplitude = persistence.powi(o);
+            let (n, dn) = sample(lacunarity.powi(o));
+
+            match self {
+                Fractal::Fbm => {
+                    value += n * amplitude;
+                    for i in 0..N {
+                        d[i] += dn[i] * amplitude;
+                    }
+                }
+                Fractal::Billow => {
+                    value += (2.0 * n.abs() - 1.0) * amplitude;
+                    for i in 0..N {
+                        d[i] += 2.0 * n.signum() * dn[i] * amplitude;
+                    }
+                }
+                Fractal::Ridged {
+                    offset,
+                    gain,
+                    sharpness,
+                } => {
+                    let ridge = (offset - n.abs()).max(0.0);
+                    let signal = ridge.powf(sharpness) * weight;
+                    // flat wherever the ridge is clipped to 0
+                    let slope = if ridge > 0.0 {
+                        -n.signum() * sharpness * ridge.powf(sharpness - 1.0)
+                    } else {
+                        0.0
+                    };
+                    let dsignal: [f32; N] = std::array::from_fn(|i| {
+                        slope * dn[i] * weight + ridge.powf(sharpness) * dweight[i]
+                    });
+
+                    let unclamped = signal * gain;
+                    weight = unclamped.clamp(0.0, 1.0);
+                    dweight = if unclamped > 0.0 && unclamped < 1.0 {
+                        dsignal.map(|ds| ds * gain)
+                    } else {
+                        [0.0; N]
+                    };
+                    value += signal * amplitude;
+                    for i in 0..N {
+                        d[i] += dsignal[i] * amplitude;
+                    }
+                }
+                Fractal::HybridMultifractal { offset } => {
+                    let signal = (n + offset) * amplitude;
+                    let dsignal = dn.map(|dn| dn * amplitude);
+                    if o == 0 {
+                        value = signal;
+                        d = dsignal;
+                        weight = signal;
+                        dweight = dsignal;
+                    } else {
+                        if weight > 1.0 {
+                            weight = 1.0;
+                            dweight = [0.0; N];
+                        }
+                        value += weight * signal;
+                        // value and weight both grow by weight * signal
+                        for i in 0..N {
+                            let dproduct = dweight[i] * signal + weight * dsignal[i];
+                            d[i] += dproduct;
+                            dweight[i] = dproduct;
+                        }
+                        weight *= signal;
+                    }
+                }
+                Fractal::HeterogeneousTerrain { offset } => {
+                    if o == 0 {
+                        value = n + offset;
+                        d = dn;
+                    } else {
+                        let signal = (n + offset) * amplitude;
+                        for i in 0..N {
+                            d[i] += dn[i] * amplitude * value + signal * d[i];
+                        }
+                        value += signal * value;
+                    }
+                }
+            }
+            max_value += amplitude;
+        }
+
+        if max_value > 0.0 {
+            (value / max_value, d.map(|d| d / max_value))
+        } else {
+            (0.0, [0.0; N])
+        }
+    }
+}
+
+// evaluates fractal perlin heightmaps at map coordinates, which run 0..1 across
+// the map, one sample at a time or in batches that give identical results
+pub struct FractalPerlinSampler {
+    params: FractalPerlinParams,
+    permutation: Vec<i32>,
+    warp: Option<DomainWarp>,
+}
+
+impl FractalPerlinSampler {
+    pub fn new(params: &FractalPerlinParams) -> Self {
+        FractalPerlinSampler {
+            params: params.clone(),
+            permutation: generate_permutation(params.seed),
+            warp: params.warp.as_ref().map(DomainWarp::new),
+        }
+    }
+
+    // the map spans one lattice cell at the first octave, so when tiling a whole
+    // number of cells per octave makes both edges meet
+    fn octave_frequency(&self, f: f32) -> (f32, Option<[usize; 3]>) {
+        if self.params.tileable {
+            let f = f.round().max(1.0);
+            (f, Some([f as usize, f as usize, 256]))
+        } else {
+            (f, None)
+        }
+    }
+
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x, y) = match &self.warp {
+            Some(warp) => warp.apply(x, y),
+            None => (x, y),
+        };
+        let p = &self.params;
+
+        p.fractal
+            .evaluate(p.octaves, p.persistence, p.lacunarity, |f| {
+                match self.octave_frequency(f) {
+                    (f, Some(period)) => {
+                        perlin3d_periodic(x * f, y * f, 0.0, period, &self.permutation)
+                    }
+                    (f, None) => perlin3d(x * f, y * f, 0.0, &self.permutation),
+                }
+            })
+            * p.scale
+    }
+
+    // sample and its partial derivatives (d/dx, d/dy) in map coordinates, so a
+    // map w cells wide changes by d[1] / w per cell along its rows
+    pub fn sample_deriv(&self, x: f32, y: f32) -> (f32, [f32; 2]) {
+        let (u, v) = match &self.warp {
+            Some(warp) => warp.apply(x, y),
+            None => (x, y),
+        };
+        let p = &self.params;
+
+        let (value, d) = p
+            .fractal
+            .evaluate_deriv(p.octaves, p.persistence, p.lacunarity, |f| {
+                let (f, d) = match self.octave_frequency(f) {
+                    (f, Some(period)) => (
+                        f,
+                        perlin3d_periodic_deriv(u * f, v * f, 0.0, period, &self.permutation),
+                    ),
+                    (f, None) => (f, perlin3d_deriv(u * f, v * f, 0.0, &self.permutation)),
+                };
+                (d.0, [d.1[0] * f, d.1[1] * f])
+            });
+
+        let d = match &self.warp {
+            Some(warp) => {
+                let [[du_dx, du_dy], [dv_dx, dv_dy]] = warp.jacobian(x, y);
+                [d[0] * du_dx + d[1] * dv_dx, d[0] * du_dy + d[1] * dv_dy]
+            }
+            None => d,
+        };
+
+        (value * p.scale, d.map(|d| d * p.scale))
+    }
+
+    pub fn sample_batch(&self, xs: &[f32], ys: &[f32], out: &mut [f32]) {
+        assert!(
+            xs.len() == out.len() && ys.len() == out.len(),
+            "Batch coordinates and output differ in length"
+        );
+        let (xs, ys): (Vec<f32>, Vec<f32>) = match &self.warp {
+            Some(warp) => xs.iter().zip(ys).map(|(&x, &y)| warp.apply(x, y)).unzip(),
+            None => (xs.to_vec(), ys.to_vec()),
+        };
+        let p = &self.params;
+
+        let mut fx = vec![0.0; out.len()];
+        let mut fy = vec![0.0; out.len()];
+        let fz = vec![0.0; out.len()];
+        p.fractal.evaluate_batch(
+            p.octaves,
+            p.persistence,
+            p.lacunarity,
+            |f, noise| {
+                let (f, period) = self.octave_frequency(f);
+                for (s, &x) in fx.iter_mut().zip(&xs) {
+                    *s = x * f;
+                }
+                for (s, &y) in fy.iter_mut().zip(&ys) {
+                    *s = y * f;
+                }
+                perlin3d_batch(&fx, &fy, &fz, period, &self.permutation, noise);
+            },
+            out,
+        );
+
+        for o in out.iter_mut() {
+            *o *= p.scale;
+        }
+    }
+
+    // fills a tile of out.len() / width rows and width columns whose first cell
+    // is at (column, row) of the map
+    pub fn fill_tile(&self, column: usize, row: usize, width: usize, out: &mut [f32]) {
+        let p = &self.params;
+        let mut xs = Vec::with_capacity(out.len());
+        let mut ys = Vec::with_capacity(out.len());
+        for (k, _) in out.iter().enumerate() {
+            let (i, j) = (row + k / width, column + k % width);
+            xs.push(i as f32 / p.height as f32);
+            ys.push(j as f32 / p.width as f32);
+        }
+
+        self.sample_batch(&xs, &ys, out);
+    }
+
+    pub fn fill_row(&self, row: usize, out: &mut [f32]) {
+        self.fill_tile(0, row, out.len(), out);
+    }
+}
+
 pub fn generate_permutation(seed: u32) -> Vec<i32> {
     let mut p: Vec<i32> = (0..256).collect();
     let mut s = seed as u64;
@@ -193,3 +1115,112 @@ pub fn generate_permutation(seed: u32) -> Vec<i32> {
     let doubled: Vec<i32> = p.iter().chain(p.iter()).copied().collect();
     return doubled;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(values: &[f32]) -> Vec<u32> {
+        values.iter().map(|v| v.to_bits()).collect()
+    }
+
+    #[test]
+    fn batch_matches_scalar_perlin3d() {
+        let p = generate_permutation(3);
+        // crosses zero so negative lattice cells are covered, and is not a
+        // multiple of the lane count so the last chunk is partial
+        let xs: Vec<f32> = (0..37).map(|k| k as f32 * 0.37 - 6.0).collect();
+        let ys: Vec<f32> = (0..37).map(|k| 5.0 - k as f32 * 0.29).collect();
+        let zs: Vec<f32> = (0..37).map(|k| k as f32 * 0.11 - 2.0).collect();
+
+        let scalar: Vec<f32> = (0..xs.len())
+            .map(|k| perlin3d(xs[k], ys[k], zs[k], &p))
+            .collect();
+        let mut batch = vec![0.0; xs.len()];
+        perlin3d_batch(&xs, &ys, &zs, None, &p, &mut batch);
+        assert_eq!(bits(&scalar), bits(&batch));
+
+        let period = [3, 5, 256];
+        let scalar: Vec<f32> = (0..xs.len())
+            .map(|k| perlin3d_periodic(xs[k], ys[k], zs[k], period, &p))
+            .collect();
+        perlin3d_batch(&xs, &ys, &zs, Some(period), &p, &mut batch);
+        assert_eq!(bits(&scalar), bits(&batch));
+    }
+
+    #[test]
+    fn periods_above_256_do_not_repeat_early() {
+        let p = generate_permutation(3);
+        let at = |x: f32| perlin3d_periodic(x, 0.5, 0.5, [512, 256, 256], &p);
+        let xs: Vec<f32> = (0..64).map(|k| k as f32 * 0.73 + 0.1).collect();
+
+        assert!(xs.iter().any(|&x| at(x) != at(x + 256.0)));
+        assert!(xs.iter().all(|&x| (at(x) - at(x + 512.0)).abs() < 1e-3));
+    }
+
+    #[test]
+    fn tileable_maps_wrap_on_both_axes() {
+        // lacunarity 2 over 10 octaves reaches a period of 512 cells
+        let params = FractalPerlinParams {
+            octaves: 10,
+            tileable: true,
+            seed: 4,
+            ..Default::default()
+        };
+        let sampler = FractalPerlinSampler::new(&params);
+
+        for k in 0..32 {
+            let t = k as f32 / 32.0;
+            let x = sampler.sample(0.0, t) - sampler.sample(1.0, t);
+            let z = sampler.sample(t, 0.0) - sampler.sample(t, 1.0);
+            assert!(x.abs() < 1e-3 && z.abs() < 1e-3, "{} {} at {}", x, z, t);
+        }
+    }
+
+    #[test]
+    fn sampler_rows_match_scalar_samples() {
+        let base = FractalPerlinParams {
+            width: 37,
+            height: 9,
+            octaves: 5,
+            seed: 11,
+            ..Default::default()
+        };
+        let cases = [
+            base.clone(),
+            FractalPerlinParams {
+                fractal: Fractal::Ridged {
+                    offset: 1.0,
+                    gain: 2.0,
+                    sharpness: 2.0,
+                },
+                ..base.clone()
+            },
+            FractalPerlinParams {
+                tileable: true,
+                ..base.clone()
+            },
+            FractalPerlinParams {
+                warp: Some(DomainWarpParams::default()),
+                ..base.clone()
+            },
+        ];
+
+        for params in &cases {
+            let sampler = FractalPerlinSampler::new(params);
+            let mut row = vec![0.0; params.width];
+            for i in 0..params.height {
+                sampler.fill_row(i, &mut row);
+                let scalar: Vec<f32> = (0..params.width)
+                    .map(|j| {
+                        sampler.sample(
+                            i as f32 / params.height as f32,
+                            j as f32 / params.width as f32,
+                        )
+                    })
+                    .collect();
+                assert_eq!(bits(&scalar), bits(&row), "row {}", i);
+            }
+        }
+    }
+}
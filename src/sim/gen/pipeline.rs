@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{Context, Result, bail, ensure};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::dla::{DiffusionLimitedAggregationMode, DiffusionLimitedAggregationParams};
+use super::filter::{BorderMode, Filter};
+use super::lib::{Algorithms, HeightMap};
+use super::perlin::{FractalPerlinParams, GradientFractalPerlinParams};
+use super::simplex::SimplexParams;
+use super::worley::WorleyParams;
+
+// a named set of nodes, evaluating the recipe produces the output node's heightmap
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recipe {
+    pub output: String,
+    pub nodes: BTreeMap<String, Node>,
+}
+
+// every node emits a heightmap, inputs refer to other nodes by name
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Node {
+    Constant {
+        width: usize,
+        height: usize,
+        #[serde(default)]
+        value: f32,
+    },
+    FractalPerlin(FractalPerlinParams),
+    GradientFractalPerlin(GradientFractalPerlinParams),
+    Dla(DlaNode),
+    Simplex(SimplexParams),
+    Worley(WorleyParams),
+    Filter {
+        input: String,
+        filter: Filter,
+        #[serde(default)]
+        border: BorderMode,
+    },
+    Add {
+        inputs: Vec<String>,
+    },
+    Subtract {
+        input: String,
+        other: String,
+    },
+    Multiply {
+        input: String,
+        mask: String,
+    },
+    Scale {
+        input: String,
+        factor: f32,
+    },
+    Offset {
+        input: String,
+        amount: f32,
+    },
+    Min {
+        inputs: Vec<String>,
+    },
+    Max {
+        inputs: Vec<String>,
+    },
+    Blend {
+        input: String,
+        other: String,
+        mask: String,
+    },
+    Clamp {
+        input: String,
+        min: f32,
+        max: f32,
+    },
+    Remap {
+        input: String,
+        from: (f32, f32),
+        to: (f32, f32),
+    },
+    Normalize {
+        input: String,
+        min: f32,
+        max: f32,
+    },
+    Power {
+        input: String,
+        exponent: f32,
+    },
+    Terrace {
+        input: String,
+        levels: u32,
+        sharpness: f32,
+    },
+    Resample {
+        input: String,
+        width: usize,
+        height: usize,
+    },
+}
+
+// params sit inline like every other generator node
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "RawDlaNode")]
+pub struct DlaNode {
+    #[serde(flatten)]
+    pub params: DiffusionLimitedAggregationParams,
+    #[serde(default)]
+    pub mask: Option<PathBuf>, // runs in image mode seeded from this mask when set
+}
+
+// serde ignores deny_unknown_fields next to a flattened struct, so whatever the
+// params and mask leave over is collected here and rejected
+#[derive(Deserialize)]
+struct RawDlaNode {
+    #[serde(flatten)]
+    params: DiffusionLimitedAggregationParams,
+    #[serde(default)]
+    mask: Option<PathBuf>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+impl TryFrom<RawDlaNode> for DlaNode {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawDlaNode) -> Result<Self> {
+        ensure!(
+            raw.unknown.is_empty(),
+            "Unknown dla fields: {}",
+            raw.unknown.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+
+        Ok(DlaNode {
+            params: raw.params,
+            mask: raw.mask,
+        })
+    }
+}
+
+// evaluates recipes, keeping every node's result so re-running an edited recipe
+// only recomputes the nodes that changed and the nodes downstream of them
+#[derive(Default)]
+pub struct Pipeline {
+    cache: HashMap<String, (u64, Rc<HeightMap>)>,
+}
+
+impl Recipe {
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(text)?)
+    }
+
+    pub fn from_ron(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    // enums inside nodes are written as single key maps rather than yaml tags so
+    // the output can be read back
+    pub fn to_yaml(&self) -> Result<String> {
+        let mut out = Vec::new();
+        serde_yaml::with::singleton_map_recursive::serialize(
+            self,
+            &mut serde_yaml::Serializer::new(&mut out),
+        )?;
+
+        Ok(String::from_utf8(out)?)
+    }
+
+    // .ron files are read as RON, anything else as YAML. Enums inside a node are
+    // written as single key maps in both, e.g. filter: { box: { radius: 2 } }
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read recipe {}", path.display()))?;
+
+        let recipe = match path.extension().and_then(|e| e.to_str()) {
+            Some("ron") => Self::from_ron(&text),
+            _ => Self::from_yaml(&text),
+        };
+        recipe.with_context(|| format!("Failed to parse recipe {}", path.display()))
+    }
+
+    // nodes needed by the output in evaluation order, fails on missing inputs,
+    // cycles, malformed filter kernels and inputs whose sizes do not match
+    pub fn check(&self) -> Result<Vec<String>> {
+        let mut order = Vec::new();
+        let mut visiting = Vec::new();
+        self.visit(&self.output, &mut visiting, &mut order)?;
+
+        let mut sizes: HashMap<&str, (usize, usize)> = HashMap::new();
+        for name in &order {
+            let size = self.nodes[name]
+                .output_size(&sizes)
+                .with_context(|| format!("Node '{}' is invalid", name))?;
+            sizes.insert(name, size);
+        }
+
+        Ok(order)
+    }
+
+    fn visit(&self, name: &str, visiting: &mut Vec<String>, order: &mut Vec<String>) -> Result<()> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if let Some(start) = visiting.iter().position(|n| n == name) {
+            bail!(
+                "Recipe has a cycle: {} -> {}",
+                visiting[start..].join(" -> "),
+                name
+            );
+        }
+        let Some(node) = self.nodes.get(name) else {
+            match visiting.last() {
+                Some(parent) => bail!("Node '{}' uses unknown input '{}'", parent, name),
+                None => bail!("Output node '{}' does not exist", name),
+            }
+        };
+
+        visiting.push(name.to_string());
+        for input in node.inputs() {
+            self.visit(input, visiting, order)?;
+        }
+        visiting.pop();
+        order.push(name.to_string());
+
+        Ok(())
+    }
+}
+
+impl Node {
+    pub fn inputs(&self) -> Vec<&str> {
+        match self {
+            Node::Constant { .. }
+            | Node::FractalPerlin(_)
+            | Node::GradientFractalPerlin(_)
+            | Node::Dla(_)
+            | Node::Simplex(_)
+            | Node::Worley(_) => Vec::new(),
+            Node::Add { inputs } | Node::Min { inputs } | Node::Max { inputs } => {
+                inputs.iter().map(|i| i.as_str()).collect()
+            }
+            Node::Subtract { input, other } => vec![input, other],
+            Node::Multiply { input, mask } => vec![input, mask],
+            Node::Blend { input, other, mask } => vec![input, other, mask],
+            Node::Filter { input, .. }
+            | Node::Scale { input, .. }
+            | Node::Offset { input, .. }
+            | Node::Clamp { input, .. }
+            | Node::Remap { input, .. }
+            | Node::Normalize { input, .. }
+            | Node::Power { input, .. }
+            | Node::Terrace { input, .. }
+            | Node::Resample { input, .. } => vec![input],
+        }
+    }
+
+    // width and height of the heightmap the node emits, given its inputs' sizes
+    fn output_size(&self, sizes: &HashMap<&str, (usize, usize)>) -> Result<(usize, usize)> {
+        if let Node::Filter { filter, .. } = self {
+            filter.validate()?;
+        }
+
+        let size = match self {
+            Node::Constant { width, height, .. } | Node::Resample { width, height, .. } => {
+                (*width, *height)
+            }
+            Node::FractalPerlin(p) => (p.width, p.height),
+            Node::GradientFractalPerlin(p) => (p.width, p.height),
+            Node::Dla(dla) => dla.params.output_size(),
+            Node::Simplex(p) => (p.width, p.height),
+            Node::Worley(p) => (p.width, p.height),
+            _ => {
+                let inputs = self.inputs();
+                ensure!(!inputs.is_empty(), "Node needs at least one input");
+
+                let size = sizes[inputs[0]];
+                for input in &inputs[1..] {
+                    ensure!(
+                        sizes[input] == size,
+                        "Input '{}' is {}x{} but '{}' is {}x{}",
+                        inputs[0],
+                        size.0,
+                        size.1,
+                        input,
+                        sizes[input].0,
+                        sizes[input].1
+                    );
+                }
+                size
+            }
+        };
+        ensure!(
+            size.0 > 0 && size.1 > 0,
+            "Output size {}x{} is empty",
+            size.0,
+            size.1
+        );
+
+        Ok(size)
+    }
+
+    fn evaluate(&self, inputs: &[Rc<HeightMap>]) -> Result<HeightMap> {
+        let fold = |f: fn(&HeightMap, &HeightMap) -> Result<HeightMap>| {
+            inputs[1..]
+                .iter()
+                .try_fold(HeightMap::clone(&inputs[0]), |acc, h| f(&acc, h))
+        };
+
+        match self {
+            Node::Constant {
+                width,
+                height,
+                value,
+            } => Ok(HeightMap::filled(*width, *height, *value)),
+            Node::FractalPerlin(p) => Ok(HeightMap::generate(Algorithms::FractalPerlin(p.clone()))),
+            Node::GradientFractalPerlin(p) => Ok(HeightMap::generate(
+                Algorithms::GradientFractalPerlin(p.clone()),
+            )),
+            Node::Dla(dla) => {
+                let mut params = dla.params.clone();
+                if let Some(mask) = &dla.mask {
+                    params.mode = DiffusionLimitedAggregationMode::from_image(mask)?;
+                }
+                Ok(HeightMap::generate(
+                    Algorithms::DiffusionLimitedAggregation(params),
+                ))
+            }
+            Node::Simplex(p) => Ok(HeightMap::generate(Algorithms::Simplex(p.clone()))),
+            Node::Worley(p) => Ok(HeightMap::generate(Algorithms::Worley(p.clone()))),
+            Node::Filter { filter, border, .. } => inputs[0].filter(filter, *border),
+            Node::Add { .. } => fold(|a, b| a + b),
+            Node::Min { .. } => fold(HeightMap::min),
+            Node::Max { .. } => fold(HeightMap::max),
+            Node::Subtract { .. } => &*inputs[0] - &*inputs[1],
+            Node::Multiply { .. } => &*inputs[0] * &*inputs[1],
+            Node::Blend { .. } => inputs[0].blend(&inputs[1], &inputs[2]),
+            Node::Scale { factor, .. } => Ok(inputs[0].scaled(*factor)),
+            Node::Offset { amount, .. } => Ok(inputs[0].offset(*amount)),
+            Node::Clamp { min, max, .. } => inputs[0].clamp(*min, *max),
+            Node::Remap { from, to, .. } => inputs[0].remap(*from, *to),
+            Node::Normalize { min, max, .. } => inputs[0].normalize(*min, *max),
+            Node::Power { exponent, .. } => inputs[0].power(*exponent),
+            Node::Terrace {
+                levels, sharpness, ..
+            } => inputs[0].terrace(*levels, *sharpness),
+            Node::Resample { width, height, .. } => inputs[0].resample(*width, *height),
+        }
+    }
+
+    // changes whenever the node's definition or anything upstream of it changes
+    fn cache_key(&self, input_keys: &[u64]) -> Result<u64> {
+        let mut hasher = DefaultHasher::new();
+        serde_yaml::to_string(self)?.hash(&mut hasher);
+        input_keys.hash(&mut hasher);
+        if let Node::Dla(DlaNode {
+            mask: Some(mask), ..
+        }) = self
+        {
+            std::fs::metadata(mask)
+                .and_then(|m| m.modified())
+                .ok()
+                .hash(&mut hasher);
+        }
+
+        Ok(hasher.finish())
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn evaluate(&mut self, recipe: &Recipe) -> Result<Rc<HeightMap>> {
+        let order = recipe.check()?;
+        let mut keys: HashMap<&str, u64> = HashMap::new();
+        let mut results: HashMap<&str, Rc<HeightMap>> = HashMap::new();
+
+        for name in &order {
+            let node = &recipe.nodes[name];
+            let input_names = node.inputs();
+            let input_keys: Vec<u64> = input_names.iter().map(|i| keys[i]).collect();
+            let key = node.cache_key(&input_keys)?;
+
+            let map = match self.cache.get(name) {
+                Some((cached, map)) if *cached == key => {
+                    debug!("Reusing cached node '{}'", name);
+                    map.clone()
+                }
+                _ => {
+                    debug!("Evaluating node '{}'", name);
+                    let inputs: Vec<Rc<HeightMap>> =
+                        input_names.iter().map(|i| results[i].clone()).collect();
+                    let map = Rc::new(
+                        node.evaluate(&inputs)
+                            .with_context(|| format!("Failed to evaluate node '{}'", name))?,
+                    );
+                    self.cache.insert(name.clone(), (key, map.clone()));
+                    map
+                }
+            };
+
+            keys.insert(name, key);
+            results.insert(name, map);
+        }
+
+        // drop nodes that were removed from the recipe or are no longer used
+        self.cache
+            .retain(|name, _| results.contains_key(name.as_str()));
+
+        Ok(results[recipe.output.as_str()].clone())
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+}
@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+
+use super::warp::DomainWarpParams;
+
+// edge midpoints of a cube, shared by the 2d and 3d noise
+const GRAD3: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimplexParams {
+    pub height: usize,
+    pub width: usize,
+    pub scale: f32,
+    pub frequency: f32, // lattice cells across the map at the first octave
+    pub octaves: i32,
+    pub persistence: f32,
+    pub lacunarity: f32,
+    pub z: Option<f32>, // samples a slice of 3d noise at this depth when set
+    pub seed: u32,
+    pub warp: Option<DomainWarpParams>,
+}
+
+impl Default for SimplexParams {
+    fn default() -> Self {
+        SimplexParams {
+            height: 256,
+            width: 256,
+            scale: 40.0,
+            frequency: 4.0,
+            octaves: 6,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            z: None,
+            seed: 0,
+            warp: None,
+        }
+    }
+}
+
+fn gradient(hash: i32) -> [f32; 3] {
+    GRAD3[(hash % 12) as usize]
+}
+
+// p is a doubled permutation table from perlin::generate_permutation, output is
+// roughly in -1..1
+pub fn simplex2d(x: f32, y: f32, p: &[i32]) -> f32 {
+    let f2 = 0.5 * (3.0f32.sqrt() - 1.0);
+    let g2 = (3.0 - 3.0f32.sqrt()) / 6.0;
+
+    // skew into the simplex grid to find the containing cell
+    let s = (x + y) * f2;
+    let i = (x + s).floor();
+    let j = (y + s).floor();
+    let t = (i + j) * g2;
+    let x0 = x - (i - t);
+    let y0 = y - (j - t);
+
+    // the lower or upper triangle of the cell
+    let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+
+    let corners = [
+        (x0, y0),
+        (x0 - i1 as f32 + g2, y0 - j1 as f32 + g2),
+        (x0 - 1.0 + 2.0 * g2, y0 - 1.0 + 2.0 * g2),
+    ];
+
+    let ii = i as i32 as usize & 255;
+    let jj = j as i32 as usize & 255;
+    let hashes = [
+        p[ii + p[jj] as usize],
+        p[ii + i1 + p[jj + j1] as usize],
+        p[ii + 1 + p[jj + 1] as usize],
+    ];
+
+    let mut value = 0.0;
+    for ((cx, cy), hash) in corners.into_iter().zip(hashes) {
+        let t = 0.5 - cx * cx - cy * cy;
+        if t > 0.0 {
+            let g = gradient(hash);
+            value += t.powi(4) * (g[0] * cx + g[1] * cy);
+        }
+    }
+
+    70.0 * value
+}
+
+pub fn simplex3d(x: f32, y: f32, z: f32, p: &[i32]) -> f32 {
+    let f3 = 1.0 / 3.0;
+    let g3 = 1.0 / 6.0;
+
+    let s = (x + y + z) * f3;
+    let i = (x + s).floor();
+    let j = (y + s).floor();
+    let k = (z + s).floor();
+    let t = (i + j + k) * g3;
+    let x0 = x - (i - t);
+    let y0 = y - (j - t);
+    let z0 = z - (k - t);
+
+    // the second and third corners of the tetrahedron, picked by the order of
+    // the offsets along each axis
+    let ([i1, j1, k1], [i2, j2, k2]) = if x0 >= y0 {
+        if y0 >= z0 {
+            ([1, 0, 0], [1, 1, 0])
+        } else if x0 >= z0 {
+            ([1, 0, 0], [1, 0, 1])
+        } else {
+            ([0, 0, 1], [1, 0, 1])
+        }
+    } else if y0 < z0 {
+        ([0, 0, 1], [0, 1, 1])
+    } else if x0 < z0 {
+        ([0, 1, 0], [0, 1, 1])
+    } else {
+        ([0, 1, 0], [1, 1, 0])
+    };
+
+    let corners = [
+        (x0, y0, z0),
+        (
+            x0 - i1 as f32 + g3,
+            y0 - j1 as f32 + g3,
+            z0 - k1 as f32 + g3,
+        ),
+        (
+            x0 - i2 as f32 + 2.0 * g3,
+            y0 - j2 as f32 + 2.0 * g3,
+            z0 - k2 as f32 + 2.0 * g3,
+        ),
+        (
+            x0 - 1.0 + 3.0 * g3,
+            y0 - 1.0 + 3.0 * g3,
+            z0 - 1.0 + 3.0 * g3,
+        ),
+    ];
+
+    let ii = i as i32 as usize & 255;
+    let jj = j as i32 as usize & 255;
+    let kk = k as i32 as usize & 255;
+    let hash =
+        |di: usize, dj: usize, dk: usize| p[ii + di + p[jj + dj + p[kk + dk] as usize] as usize];
+    let hashes = [
+        hash(0, 0, 0),
+        hash(i1, j1, k1),
+        hash(i2, j2, k2),
+        hash(1, 1, 1),
+    ];
+
+    let mut value = 0.0;
+    for ((cx, cy, cz), hash) in corners.into_iter().zip(hashes) {
+        let t = 0.6 - cx * cx - cy * cy - cz * cz;
+        if t > 0.0 {
+            let g = gradient(hash);
+            value += t.powi(4) * (g[0] * cx + g[1] * cy + g[2] * cz);
+        }
+    }
+
+    32.0 * value
+}
+
+// fbm normalised by the summed amplitudes, so the output stays in -1..1
+pub fn octave_simplex2d(
+    x: f32,
+    y: f32,
+    octaves: i32,
+    persistence: f32,
+    lacunarity: f32,
+    permutation: &[i32],
+) -> f32 {
+    let mut value = 0.0;
+    let mut max_value = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+
+    for _ in 0..octaves {
+        value += simplex2d(x * frequency, y * frequency, permutation) * amplitude;
+        max_value += amplitude;
+        amplitude *= persistence;
+        frequency *= lacunarity;
+    }
+
+    if max_value > 0.0 {
+        value / max_value
+    } else {
+        0.0
+    }
+}
+
+pub fn octave_simplex3d(
+    x: f32,
+    y: f32,
+    z: f32,
+    octaves: i32,
+    persistence: f32,
+    lacunarity: f32,
+    permutation: &[i32],
+) -> f32 {
+    let mut value = 0.0;
+    let mut max_value = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+
+    for _ in 0..octaves {
+        value += simplex3d(x * frequency, y * frequency, z * frequency, permutation) * amplitude;
+        max_value += amplitude;
+        amplitude *= persistence;
+        frequency *= lacunarity;
+    }
+
+    if max_value > 0.0 {
+        value / max_value
+    } else {
+        0.0
+    }
+}
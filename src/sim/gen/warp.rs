@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use super::perlin::{self, Fractal};
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarpKind {
+    // offsets coordinates by two independent fbm fields
+    #[default]
+    Fbm,
+    // offsets along the curl of an fbm field, which swirls without bunching up
+    Curl,
+}
+
+// warps map coordinates, which run 0..1 across the map, before a generator
+// samples them. Warping does not keep tileable maps tileable
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DomainWarpParams {
+    pub kind: WarpKind,
+    pub strength: f32,  // largest offset as a fraction of the map
+    pub frequency: f32, // lattice cells of the warp field across the map
+    pub octaves: i32,
+    pub persistence: f32,
+    pub levels: u32, // 1 warps once, every further level warps the warp field itself
+    pub seed: u32,
+}
+
+impl Default for DomainWarpParams {
+    fn default() -> Self {
+        DomainWarpParams {
+            kind: WarpKind::Fbm,
+            strength: 0.1,
+            frequency: 4.0,
+            octaves: 4,
+            persistence: 0.5,
+            levels: 1,
+            seed: 0,
+        }
+    }
+}
+
+pub struct DomainWarp {
+    params: DomainWarpParams,
+    permutation_x: Vec<i32>,
+    permutation_y: Vec<i32>,
+}
+
+impl DomainWarp {
+    pub fn new(params: &DomainWarpParams) -> Self {
+        DomainWarp {
+            params: params.clone(),
+            permutation_x: perlin::generate_permutation(params.seed),
+            permutation_y: perlin::generate_permutation(params.seed.wrapping_add(1)),
+        }
+    }
+
+    // p + strength * field(p + strength * field(p + ...)), levels deep
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        let strength = self.params.strength;
+        let mut offset = (0.0, 0.0);
+        for level in 0..self.params.levels {
+            // shifts each level so nested lookups do not land on the same values
+            let shift = level as f32 * 5.2;
+            offset = self.field(
+                x + strength * offset.0 + shift,
+                y + strength * offset.1 + shift,
+            );
+        }
+
+        (x + strength * offset.0, y + strength * offset.1)
+    }
+
+    // [[du/dx, du/dy], [dv/dx, dv/dy]] of apply(x, y) = (u, v). The warp fields
+    // have no analytic derivatives, so this is a central difference
+    pub fn jacobian(&self, x: f32, y: f32) -> [[f32; 2]; 2] {
+        const STEP: f32 = 1e-3;
+
+        let (x0, y0) = self.apply(x - STEP, y);
+        let (x1, y1) = self.apply(x + STEP, y);
+        let (x2, y2) = self.apply(x, y - STEP);
+        let (x3, y3) = self.apply(x, y + STEP);
+
+        [
+            [(x1 - x0) / (2.0 * STEP), (x3 - x2) / (2.0 * STEP)],
+            [(y1 - y0) / (2.0 * STEP), (y3 - y2) / (2.0 * STEP)],
+        ]
+    }
+
+    // offset in roughly -1..1 on both axes
+    fn field(&self, x: f32, y: f32) -> (f32, f32) {
+        let DomainWarpParams {
+            frequency,
+            octaves,
+            persistence,
+            ..
+        } = self.params;
+        let (x, y) = (x * frequency, y * frequency);
+
+        match self.params.kind {
+            WarpKind::Fbm => {
+                let fbm = |p: &Vec<i32>| {
+                    Fractal::Fbm.evaluate(octaves, persistence, 2.0, |f| {
+                        perlin::perlin2d(x * f, y * f, p)
+                    })
+                };
+                (fbm(&self.permutation_x), fbm(&self.permutation_y))
+            }
+            WarpKind::Curl => {
+                // curl of a scalar potential is (d/dy, -d/dx), summed over octaves
+                let mut curl = (0.0, 0.0);
+                let mut max_value = 0.0;
+                let mut amplitude = 1.0;
+                let mut f = 1.0;
+                for _ in 0..octaves {
+                    let (_, d) = perlin::perlin2d_deriv(x * f, y * f, &self.permutation_x);
+                    curl.0 += d[1] * amplitude;
+                    curl.1 -= d[0] * amplitude;
+                    max_value += amplitude;
+                    amplitude *= persistence;
+                    f *= 2.0;
+                }
+
+                if max_value > 0.0 {
+                    (curl.0 / max_value, curl.1 / max_value)
+                } else {
+                    curl
+                }
+            }
+        }
+    }
+}
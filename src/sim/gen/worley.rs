@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+
+use super::warp::DomainWarpParams;
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceMetric {
+    #[default]
+    Euclidean,
+    Manhattan,
+    Chebyshev,
+}
+
+// f1 is the distance to the closest feature point and f2 to the second closest,
+// f2 - f1 is zero along cell borders which reads as cracks
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorleyFeature {
+    #[default]
+    F1,
+    F2,
+    F2MinusF1,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorleyParams {
+    pub height: usize,
+    pub width: usize,
+    pub scale: f32,
+    pub frequency: f32, // cells across the map
+    pub jitter: f32,    // 0 puts every feature point at its cell centre, 1 anywhere in the cell
+    pub metric: DistanceMetric,
+    pub feature: WorleyFeature,
+    pub z: Option<f32>, // samples a slice of 3d noise at this depth when set
+    pub seed: u32,
+    pub warp: Option<DomainWarpParams>,
+}
+
+impl Default for WorleyParams {
+    fn default() -> Self {
+        WorleyParams {
+            height: 256,
+            width: 256,
+            scale: 40.0,
+            frequency: 8.0,
+            jitter: 1.0,
+            metric: DistanceMetric::Euclidean,
+            feature: WorleyFeature::F1,
+            z: None,
+            seed: 0,
+            warp: None,
+        }
+    }
+}
+
+impl DistanceMetric {
+    pub fn distance(self, d: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::Euclidean => d.iter().map(|v| v * v).sum::<f32>().sqrt(),
+            DistanceMetric::Manhattan => d.iter().map(|v| v.abs()).sum(),
+            DistanceMetric::Chebyshev => d.iter().fold(0.0, |m, v| m.max(v.abs())),
+        }
+    }
+}
+
+impl WorleyFeature {
+    pub fn select(self, [f1, f2]: [f32; 2]) -> f32 {
+        match self {
+            WorleyFeature::F1 => f1,
+            WorleyFeature::F2 => f2,
+            WorleyFeature::F2MinusF1 => f2 - f1,
+        }
+    }
+}
+
+// murmur3 finaliser
+fn mix(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+fn hash_cell(cell: [i32; 3], seed: u32) -> u32 {
+    mix(seed.wrapping_mul(0x9e37_79b9)
+        ^ (cell[0] as u32).wrapping_mul(0x8da6_b343)
+        ^ (cell[1] as u32).wrapping_mul(0xd816_3841)
+        ^ (cell[2] as u32).wrapping_mul(0xcb1a_b31f))
+}
+
+// 0..1 from the top 24 bits
+fn unit(h: u32) -> f32 {
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+// keeps the two smallest distances seen so far
+fn push_distance(closest: &mut [f32; 2], d: f32) {
+    if d < closest[0] {
+        closest[1] = closest[0];
+        closest[0] = d;
+    } else if d < closest[1] {
+        closest[1] = d;
+    }
+}
+
+// the feature point of a unit cell, jitter 0 puts it at the cell centre
+fn feature_point(cell: [i32; 3], jitter: f32, seed: u32) -> [f32; 3] {
+    let h = hash_cell(cell, seed);
+    let hy = mix(h);
+    let hz = mix(hy);
+
+    [
+        cell[0] as f32 + 0.5 + (unit(h) - 0.5) * jitter,
+        cell[1] as f32 + 0.5 + (unit(hy) - 0.5) * jitter,
+        cell[2] as f32 + 0.5 + (unit(hz) - 0.5) * jitter,
+    ]
+}
+
+// distance along one axis from t to the span the feature point of cell c can take
+fn gap(t: f32, c: i32, jitter: f32) -> f32 {
+    let lo = c as f32 + 0.5 - 0.5 * jitter;
+    let hi = c as f32 + 0.5 + 0.5 * jitter;
+    (lo - t).max(t - hi).max(0.0)
+}
+
+// one feature point per unit cell, returns the distances [f1, f2]. A jittered
+// point two cells away can be nearer than every point in the 3x3 block, so the
+// ring around it is searched too, skipping cells too far away to matter. Exact
+// for f1, f2 can still miss a point further out in rare corners
+pub fn worley2d(x: f32, y: f32, jitter: f32, metric: DistanceMetric, seed: u32) -> [f32; 2] {
+    let jitter = jitter.clamp(0.0, 1.0);
+    let (cx, cy) = (x.floor() as i32, y.floor() as i32);
+    let mut closest = [f32::INFINITY; 2];
+
+    for ring in 0..=2i32 {
+        for dy in -ring..=ring {
+            for dx in -ring..=ring {
+                if dx.abs().max(dy.abs()) != ring {
+                    continue;
+                }
+                let cell = [cx + dx, cy + dy, 0];
+                let bound = metric.distance(&[gap(x, cell[0], jitter), gap(y, cell[1], jitter)]);
+                if ring == 2 && bound >= closest[1] {
+                    continue;
+                }
+
+                let [px, py, _] = feature_point(cell, jitter, seed);
+                push_distance(&mut closest, metric.distance(&[px - x, py - y]));
+            }
+        }
+    }
+
+    closest
+}
+
+pub fn worley3d(
+    x: f32,
+    y: f32,
+    z: f32,
+    jitter: f32,
+    metric: DistanceMetric,
+    seed: u32,
+) -> [f32; 2] {
+    let jitter = jitter.clamp(0.0, 1.0);
+    let (cx, cy, cz) = (x.floor() as i32, y.floor() as i32, z.floor() as i32);
+    let mut closest = [f32::INFINITY; 2];
+
+    for ring in 0..=2i32 {
+        for dz in -ring..=ring {
+            for dy in -ring..=ring {
+                for dx in -ring..=ring {
+                    if dx.abs().max(dy.abs()).max(dz.abs()) != ring {
+                        continue;
+                    }
+                    let cell = [cx + dx, cy + dy, cz + dz];
+                    let bound = metric.distance(&[
+                        gap(x, cell[0], jitter),
+                        gap(y, cell[1], jitter),
+                        gap(z, cell[2], jitter),
+                    ]);
+                    if ring == 2 && bound >= closest[1] {
+                        continue;
+                    }
+
+                    let [px, py, pz] = feature_point(cell, jitter, seed);
+                    push_distance(&mut closest, metric.distance(&[px - x, py - y, pz - z]));
+                }
+            }
+        }
+    }
+
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nearest feature point over a 7x7 block, far wider than any point can reach
+    fn brute_f1(x: f32, y: f32, metric: DistanceMetric, seed: u32) -> f32 {
+        let (cx, cy) = (x.floor() as i32, y.floor() as i32);
+        let mut f1 = f32::INFINITY;
+        for dy in -3..=3 {
+            for dx in -3..=3 {
+                let [px, py, _] = feature_point([cx + dx, cy + dy, 0], 1.0, seed);
+                f1 = f1.min(metric.distance(&[px - x, py - y]));
+            }
+        }
+        f1
+    }
+
+    #[test]
+    fn f1_matches_a_brute_force_search() {
+        for metric in [
+            DistanceMetric::Euclidean,
+            DistanceMetric::Manhattan,
+            DistanceMetric::Chebyshev,
+        ] {
+            for k in 0..4000 {
+                // samples hug cell corners, where far points are most likely to win
+                let x = (k % 200) as f32 * 0.5 + 0.01 * (k % 3) as f32;
+                let y = (k / 200) as f32 * 0.5 + 0.99 * (k % 2) as f32;
+                assert_eq!(
+                    worley2d(x, y, 1.0, metric, 9)[0],
+                    brute_f1(x, y, metric, 9),
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
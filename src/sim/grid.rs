@@ -0,0 +1,14 @@
+// offsets to the 8 neighbours of a cell as (dx, dz)
+// 0   1   2
+// 3   x   4
+// 5   6   7
+pub const NEIGHBOURS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
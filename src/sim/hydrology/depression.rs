@@ -0,0 +1,345 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+use crate::sim::r#gen::lib::{HeightMap, HeightMapMesh};
+use crate::sim::grid::NEIGHBOURS;
+
+// a filled depression
+#[derive(Clone, Debug)]
+pub struct Lake {
+    pub cells: Vec<usize>, // row major cell indices under water
+    pub level: f32,        // height of the water surface
+    pub volume: f32,       // height units times cell area
+    pub max_depth: f32,
+    pub spill: (usize, usize), // (x, z) of the rim cell the lake overflows through
+}
+
+// cell waiting in the priority flood, lowest height first and then in the order
+// cells were queued so results do not depend on the heap
+#[derive(Copy, Clone, PartialEq)]
+struct Queued {
+    height: f32,
+    seq: usize,
+    cell: usize,
+}
+
+impl Eq for Queued {}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.height
+            .total_cmp(&other.height)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Flood {
+    filled: Vec<f32>,
+    // cell each cell was reached from, None on the map edge
+    parent: Vec<Option<usize>>,
+    // position of each cell in the order cells left the queue
+    rank: Vec<usize>,
+}
+
+// priority flood (Barnes et al. 2014) inwards from the map edge. epsilon above 0
+// raises filled cells just above the cell they were reached from, so flats drain
+fn priority_flood(map: &HeightMap, epsilon: f32) -> Flood {
+    let (width, height) = (map.width(), map.height());
+    let mut filled = map.as_slice().to_vec();
+    let mut parent = vec![None; filled.len()];
+    let mut rank = vec![usize::MAX; filled.len()];
+    let mut closed = vec![false; filled.len()];
+    let mut open = BinaryHeap::new();
+    let mut seq = 0;
+
+    for (x, z, h) in map.cells() {
+        if x == 0 || z == 0 || x + 1 == width || z + 1 == height {
+            let cell = z * width + x;
+            closed[cell] = true;
+            open.push(Reverse(Queued {
+                height: h,
+                seq,
+                cell,
+            }));
+            seq += 1;
+        }
+    }
+
+    let mut popped = 0;
+    while let Some(Reverse(Queued { cell, .. })) = open.pop() {
+        rank[cell] = popped;
+        popped += 1;
+
+        let (x, z) = ((cell % width) as i32, (cell / width) as i32);
+        for (dx, dz) in NEIGHBOURS {
+            let (nx, nz) = (x + dx, z + dz);
+            if nx < 0 || nz < 0 || nx >= width as i32 || nz >= height as i32 {
+                continue;
+            }
+            let n = nz as usize * width + nx as usize;
+            if closed[n] {
+                continue;
+            }
+            closed[n] = true;
+            parent[n] = Some(cell);
+
+            if filled[n] <= filled[cell] {
+                filled[n] = if epsilon > 0.0 {
+                    (filled[cell] + epsilon).max(filled[cell].next_up())
+                } else {
+                    filled[cell]
+                };
+            }
+            open.push(Reverse(Queued {
+                height: filled[n],
+                seq,
+                cell: n,
+            }));
+            seq += 1;
+        }
+    }
+
+    Flood {
+        filled,
+        parent,
+        rank,
+    }
+}
+
+// raises every closed depression to its spill height. With epsilon above 0 the
+// filled surface slopes down towards the spill by epsilon per cell, so every cell
+// has a downslope neighbour for flow routing
+pub fn fill_depressions(map: &HeightMap, epsilon: f32) -> HeightMap {
+    let filled = priority_flood(map, epsilon).filled;
+    HeightMap::from_vec(map.width(), map.height(), filled).unwrap()
+}
+
+// carves a channel from the bottom of each depression out over its spill, so
+// water leaves pits without raising them. Depressions whose channel would cut
+// deeper than max_depth are filled instead, as is anything carving leaves closed
+pub fn breach_depressions(map: &HeightMap, epsilon: f32, max_depth: Option<f32>) -> HeightMap {
+    let flood = priority_flood(map, 0.0);
+    let width = map.width();
+    let mut heights = map.as_slice().to_vec();
+
+    // lowest pits first, so a channel that runs into an earlier one keeps falling.
+    // Dry flats are left for the fill
+    let mut pits: Vec<usize> = (0..heights.len())
+        .filter(|&i| flood.filled[i] > heights[i] && is_pit(map, i % width, i / width))
+        .collect();
+    pits.sort_by(|&a, &b| heights[a].total_cmp(&heights[b]));
+
+    for pit in pits {
+        let mut channel = Vec::new();
+        let mut level = heights[pit];
+        let mut cell = pit;
+        while let Some(next) = flood.parent[cell] {
+            let target = level - epsilon;
+            // already below the channel, the rest of the path falls from here
+            if heights[next] < level && heights[next] <= target {
+                break;
+            }
+            channel.push((next, target));
+            level = target;
+            cell = next;
+        }
+
+        let depth = channel
+            .iter()
+            .map(|&(c, target)| heights[c] - target)
+            .fold(0.0, f32::max);
+        if max_depth.is_none_or(|max| depth <= max) {
+            for (c, target) in channel {
+                heights[c] = heights[c].min(target);
+            }
+        }
+    }
+
+    let carved = HeightMap::from_vec(map.width(), map.height(), heights).unwrap();
+    fill_depressions(&carved, epsilon)
+}
+
+// no neighbour is lower
+fn is_pit(map: &HeightMap, x: usize, z: usize) -> bool {
+    let h = map[(x, z)];
+    NEIGHBOURS.iter().all(|&(dx, dz)| {
+        let (nx, nz) = (x as i32 + dx, z as i32 + dz);
+        nx >= 0 && nz >= 0 && map.get(nx as usize, nz as usize).is_some_and(|n| n >= h)
+    })
+}
+
+// depth of standing water once every depression is filled, 0 on dry cells
+pub fn lake_depths(map: &HeightMap) -> HeightMap {
+    let filled = fill_depressions(map, 0.0);
+    (&filled - map).unwrap()
+}
+
+// every filled depression as one lake, cells touching diagonally included
+pub fn find_lakes(map: &HeightMap) -> Vec<Lake> {
+    let width = map.width();
+    let flood = priority_flood(map, 0.0);
+    let heights = map.as_slice();
+    let wet: Vec<bool> = flood
+        .filled
+        .iter()
+        .zip(heights)
+        .map(|(f, h)| f > h)
+        .collect();
+
+    let mut lakes = Vec::new();
+    let mut visited = vec![false; wet.len()];
+    for start in 0..wet.len() {
+        if !wet[start] || visited[start] {
+            continue;
+        }
+
+        visited[start] = true;
+        let mut cells = Vec::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(cell) = queue.pop_front() {
+            cells.push(cell);
+            let (x, z) = ((cell % width) as i32, (cell / width) as i32);
+            for (dx, dz) in NEIGHBOURS {
+                let (nx, nz) = (x + dx, z + dz);
+                if nx < 0 || nz < 0 || map.get(nx as usize, nz as usize).is_none() {
+                    continue;
+                }
+                let n = nz as usize * width + nx as usize;
+                if wet[n] && !visited[n] {
+                    visited[n] = true;
+                    queue.push_back(n);
+                }
+            }
+        }
+
+        // the flood reached the lake from its spill, through the first wet cell
+        let first = *cells.iter().min_by_key(|&&c| flood.rank[c]).unwrap();
+        let spill = flood.parent[first].unwrap_or(first);
+        let level = flood.filled[first];
+        let (volume, max_depth) = cells.iter().fold((0.0, 0.0f32), |(v, d), &c| {
+            let depth = flood.filled[c] - heights[c];
+            (v + depth, d.max(depth))
+        });
+
+        lakes.push(Lake {
+            cells,
+            level,
+            volume,
+            max_depth,
+            spill: (spill % width, spill / width),
+        });
+    }
+
+    lakes
+}
+
+// flat water surfaces over every lake cell, one quad per cell centred on its
+// vertex in HeightMap::to_mesh and clipped to the map
+pub fn water_mesh(lakes: &[Lake], width: usize, height: usize) -> HeightMapMesh {
+    let mut mesh = HeightMapMesh {
+        vertices: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        indices: Vec::new(),
+    };
+    let (max_x, max_z) = (
+        width.saturating_sub(1) as f32,
+        height.saturating_sub(1) as f32,
+    );
+
+    for lake in lakes {
+        for &cell in &lake.cells {
+            let (x, z) = ((cell % width) as f32, (cell / width) as f32);
+            let (x0, x1) = ((x - 0.5).max(0.0), (x + 0.5).min(max_x));
+            let (z0, z1) = ((z - 0.5).max(0.0), (z + 0.5).min(max_z));
+
+            let base = mesh.vertices.len() as u32;
+            for (vx, vz) in [(x0, z0), (x1, z0), (x0, z1), (x1, z1)] {
+                mesh.vertices.push([vx, lake.level, vz]);
+                mesh.normals.push([0.0, 1.0, 0.0]);
+                mesh.uvs.push([vx / width as f32, vz / height as f32]);
+            }
+            // same winding as the terrain mesh
+            mesh.indices
+                .extend([base, base + 2, base + 1, base + 1, base + 2, base + 3]);
+        }
+    }
+
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::hydrology::flow::{FlowDirections, FlowRouting};
+
+    // a 5x5 bowl with its lowest rim cell at (4, 2) and a pit in the middle
+    fn bowl() -> HeightMap {
+        HeightMap::from_rows(&[
+            vec![5.0, 5.0, 5.0, 5.0, 5.0],
+            vec![5.0, 2.0, 2.0, 2.0, 5.0],
+            vec![5.0, 2.0, 1.0, 2.0, 3.0],
+            vec![5.0, 2.0, 2.0, 2.0, 5.0],
+            vec![5.0, 5.0, 5.0, 5.0, 5.0],
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn bowl_fills_to_its_spill() {
+        let lakes = find_lakes(&bowl());
+        assert_eq!(lakes.len(), 1);
+
+        let lake = &lakes[0];
+        let mut cells = lake.cells.clone();
+        cells.sort();
+        assert_eq!(cells, [6, 7, 8, 11, 12, 13, 16, 17, 18]);
+        assert_eq!(lake.level, 3.0);
+        assert_eq!(lake.volume, 10.0);
+        assert_eq!(lake.max_depth, 2.0);
+        assert_eq!(lake.spill, (4, 2));
+    }
+
+    #[test]
+    fn epsilon_fill_leaves_no_interior_sinks() {
+        let (width, height) = (32, 24);
+        // pits, ridges and a flat plateau the fill has to slope
+        let data = (0..width * height)
+            .map(|i| {
+                let (x, z) = ((i % width) as f32, (i / width) as f32);
+                let h = (x * 0.6).sin() * (z * 0.5).cos() + 0.4 * (x * 0.21 + z * 0.37).sin();
+                h.min(0.5)
+            })
+            .collect();
+        let map = HeightMap::from_vec(width, height, data).unwrap();
+        let filled = fill_depressions(&map, 1e-3);
+        assert!(filled.iter().zip(map.iter()).all(|(f, h)| f >= h));
+
+        let flow = FlowDirections::new(&filled, FlowRouting::D8);
+        for z in 1..height - 1 {
+            for x in 1..width - 1 {
+                assert!(!flow.outflow(x, z).is_sink(), "({x}, {z}) is a sink");
+            }
+        }
+    }
+
+    #[test]
+    fn breaching_deeper_than_max_depth_falls_back_to_fill() {
+        let map = bowl();
+
+        // cutting the rim from 3 down to below the pit is about 2 deep
+        let breached = breach_depressions(&map, 0.01, None);
+        assert_eq!(breached[(2, 2)], 1.0);
+        assert!(breached[(4, 2)] < 1.0);
+
+        let shallow = breach_depressions(&map, 0.01, Some(1.0));
+        assert_eq!(shallow.as_slice(), fill_depressions(&map, 0.01).as_slice());
+        assert!(shallow[(2, 2)] > 3.0);
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+
+use anyhow::{Result, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::sim::r#gen::lib::HeightMap;
+use crate::sim::grid::NEIGHBOURS;
+
+// D-infinity facets counter clockwise from +x, each as its cardinal and its
+// diagonal neighbour (indices into NEIGHBOURS), the facet's first edge angle and
+// the sign that turns the within-facet angle into a flow angle
+const FACETS: [(usize, usize, f32, f32); 8] = [
+    (4, 2, 0.0, 1.0),
+    (1, 2, 2.0 * FRAC_PI_4, -1.0),
+    (1, 0, 2.0 * FRAC_PI_4, 1.0),
+    (3, 0, 4.0 * FRAC_PI_4, -1.0),
+    (3, 5, 4.0 * FRAC_PI_4, 1.0),
+    (6, 5, 6.0 * FRAC_PI_4, -1.0),
+    (6, 7, 6.0 * FRAC_PI_4, 1.0),
+    (4, 7, 8.0 * FRAC_PI_4, -1.0),
+];
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowRouting {
+    // all water goes to the steepest of the 8 neighbours
+    #[default]
+    D8,
+    // water follows the steepest downslope angle and is split between the two
+    // neighbours either side of it (Tarboton 1997)
+    DInfinity,
+}
+
+// where one cell's water goes, up to two neighbours and the fraction each gets.
+// Sinks and edge outlets keep their water and have no receivers
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Outflow {
+    pub receivers: [usize; 2],
+    pub fractions: [f32; 2],
+}
+
+impl Outflow {
+    // (cell index, fraction) of every receiver that gets some water
+    pub fn iter(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        self.receivers
+            .iter()
+            .zip(&self.fractions)
+            .filter(|(_, f)| **f > 0.0)
+            .map(|(&r, &f)| (r, f))
+    }
+
+    // the receiver with the larger share, the single receiver under D8
+    pub fn main_receiver(&self) -> Option<usize> {
+        match self.fractions {
+            [a, b] if a <= 0.0 && b <= 0.0 => None,
+            [a, b] if a >= b => Some(self.receivers[0]),
+            _ => Some(self.receivers[1]),
+        }
+    }
+
+    pub fn is_sink(&self) -> bool {
+        self.main_receiver().is_none()
+    }
+}
+
+// flow routing over a heightmap, stored row major like HeightMap
+pub struct FlowDirections {
+    width: usize,
+    height: usize,
+    routing: FlowRouting,
+    outflow: Vec<Outflow>,
+    // flow angle in radians counter clockwise from +x, with -z (up the rows) at
+    // pi / 2. None where the cell has no downslope neighbour
+    angle: Vec<Option<f32>>,
+}
+
+impl FlowDirections {
+    pub fn new(map: &HeightMap, routing: FlowRouting) -> FlowDirections {
+        let (width, height) = (map.width(), map.height());
+        let mut outflow = vec![Outflow::default(); map.len()];
+        let mut angle = vec![None; map.len()];
+
+        for (x, z, h) in map.cells() {
+            let i = z * width + x;
+            let routed = match routing {
+                FlowRouting::D8 => route_d8(map, x, z, h),
+                FlowRouting::DInfinity => route_dinf(map, x, z, h),
+            };
+            if let Some((o, a)) = routed {
+                outflow[i] = o;
+                angle[i] = Some(a);
+            }
+        }
+
+        FlowDirections {
+            width,
+            height,
+            routing,
+            outflow,
+            angle,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn routing(&self) -> FlowRouting {
+        self.routing
+    }
+
+    pub fn outflow(&self, x: usize, z: usize) -> &Outflow {
+        &self.outflow[z * self.width + x]
+    }
+
+    pub fn outflows(&self) -> &[Outflow] {
+        &self.outflow
+    }
+
+    pub fn angle(&self, x: usize, z: usize) -> Option<f32> {
+        self.angle[z * self.width + x]
+    }
+
+    // flow angles as a grid, NaN where a cell has no downslope neighbour
+    pub fn angle_map(&self) -> HeightMap {
+        let angles = self.angle.iter().map(|a| a.unwrap_or(f32::NAN)).collect();
+        HeightMap::from_vec(self.width, self.height, angles).unwrap()
+    }
+
+    // cells in an order where every cell comes before all of its receivers
+    pub fn upstream_order(&self) -> Vec<usize> {
+        let mut donors = vec![0u32; self.outflow.len()];
+        for o in &self.outflow {
+            for (r, _) in o.iter() {
+                donors[r] += 1;
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..donors.len()).filter(|&i| donors[i] == 0).collect();
+        let mut order = Vec::with_capacity(donors.len());
+        while let Some(i) = queue.pop_front() {
+            order.push(i);
+            for (r, _) in self.outflow[i].iter() {
+                donors[r] -= 1;
+                if donors[r] == 0 {
+                    queue.push_back(r);
+                }
+            }
+        }
+
+        order
+    }
+
+    // number of cells draining through each cell, counting itself
+    pub fn accumulation(&self) -> HeightMap {
+        self.accumulate(|_| 1.0)
+    }
+
+    // total rainfall draining through each cell, counting its own
+    pub fn weighted_accumulation(&self, rainfall: &HeightMap) -> Result<HeightMap> {
+        ensure!(
+            rainfall.width() == self.width && rainfall.height() == self.height,
+            "Rainfall is {}x{} but flow directions are {}x{}",
+            rainfall.width(),
+            rainfall.height(),
+            self.width,
+            self.height
+        );
+
+        Ok(self.accumulate(|i| rainfall.as_slice()[i]))
+    }
+
+    fn accumulate(&self, source: impl Fn(usize) -> f32) -> HeightMap {
+        let mut acc: Vec<f32> = (0..self.outflow.len()).map(source).collect();
+        for i in self.upstream_order() {
+            let water = acc[i];
+            for (r, f) in self.outflow[i].iter() {
+                acc[r] += water * f;
+            }
+        }
+
+        HeightMap::from_vec(self.width, self.height, acc).unwrap()
+    }
+}
+
+fn neighbour(map: &HeightMap, x: usize, z: usize, n: usize) -> Option<(usize, f32)> {
+    let (dx, dz) = NEIGHBOURS[n];
+    let (nx, nz) = (x as i32 + dx, z as i32 + dz);
+    if nx < 0 || nz < 0 {
+        return None;
+    }
+    let (nx, nz) = (nx as usize, nz as usize);
+    map.get(nx, nz).map(|h| (nz * map.width() + nx, h))
+}
+
+fn direction_angle(n: usize) -> f32 {
+    let (dx, dz) = NEIGHBOURS[n];
+    (-dz as f32)
+        .atan2(dx as f32)
+        .rem_euclid(2.0 * std::f32::consts::PI)
+}
+
+fn route_d8(map: &HeightMap, x: usize, z: usize, h: f32) -> Option<(Outflow, f32)> {
+    let mut steepest: Option<(usize, usize, f32)> = None;
+    for (n, &(dx, dz)) in NEIGHBOURS.iter().enumerate() {
+        let Some((i, nh)) = neighbour(map, x, z, n) else {
+            continue;
+        };
+        let distance = if dx != 0 && dz != 0 { SQRT_2 } else { 1.0 };
+        let slope = (h - nh) / distance;
+        if slope > 0.0 && steepest.is_none_or(|(_, _, s)| slope > s) {
+            steepest = Some((n, i, slope));
+        }
+    }
+
+    steepest.map(|(n, i, _)| {
+        let outflow = Outflow {
+            receivers: [i, i],
+            fractions: [1.0, 0.0],
+        };
+        (outflow, direction_angle(n))
+    })
+}
+
+fn route_dinf(map: &HeightMap, x: usize, z: usize, h: f32) -> Option<(Outflow, f32)> {
+    // (slope, angle, outflow) of the steepest facet
+    let mut steepest: Option<(f32, f32, Outflow)> = None;
+    for &(cardinal, diagonal, base, sign) in &FACETS {
+        let (Some((i1, h1)), Some((i2, h2))) = (
+            neighbour(map, x, z, cardinal),
+            neighbour(map, x, z, diagonal),
+        ) else {
+            continue;
+        };
+
+        let s1 = h - h1;
+        let s2 = h1 - h2;
+        let (r, slope) = {
+            let r = s2.atan2(s1);
+            if r < 0.0 {
+                (0.0, s1)
+            } else if r > FRAC_PI_4 {
+                (FRAC_PI_4, (h - h2) / SQRT_2)
+            } else {
+                (r, (s1 * s1 + s2 * s2).sqrt())
+            }
+        };
+        if slope <= 0.0 || steepest.as_ref().is_some_and(|(s, _, _)| slope <= *s) {
+            continue;
+        }
+
+        let to_diagonal = r / FRAC_PI_4;
+        let outflow = Outflow {
+            receivers: [i1, i2],
+            fractions: [1.0 - to_diagonal, to_diagonal],
+        };
+        let angle = (base + sign * r).rem_euclid(2.0 * std::f32::consts::PI);
+        steepest = Some((slope, angle, outflow));
+    }
+
+    steepest.map(|(_, angle, outflow)| (outflow, angle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(width: usize, height: usize, f: impl Fn(f32, f32) -> f32) -> HeightMap {
+        let data = (0..width * height)
+            .map(|i| f((i % width) as f32, (i / width) as f32))
+            .collect();
+        HeightMap::from_vec(width, height, data).unwrap()
+    }
+
+    #[test]
+    fn d8_follows_a_tilted_plane() {
+        let flow = FlowDirections::new(&map(8, 6, |x, _| 10.0 - x), FlowRouting::D8);
+        for z in 0..6 {
+            for x in 0..7 {
+                assert_eq!(flow.outflow(x, z).main_receiver(), Some(z * 8 + x + 1));
+                assert_eq!(flow.angle(x, z), Some(0.0));
+            }
+            // the low edge has nowhere lower to go
+            assert!(flow.outflow(7, z).is_sink());
+            assert_eq!(flow.angle(7, z), None);
+        }
+    }
+
+    #[test]
+    fn dinf_splits_evenly_across_the_middle_of_a_facet() {
+        // steepest descent at pi / 8, halfway between +x and the (+x, -z) diagonal
+        let t = (FRAC_PI_4 / 2.0).tan();
+        let flow = FlowDirections::new(&map(6, 6, |x, z| -x + t * z), FlowRouting::DInfinity);
+        for z in 1..5 {
+            for x in 1..5 {
+                let o = flow.outflow(x, z);
+                assert_eq!(o.receivers, [z * 6 + x + 1, (z - 1) * 6 + x + 1]);
+                assert!((o.fractions[0] - 0.5).abs() < 1e-4, "{:?}", o.fractions);
+                assert!((o.fractions[1] - 0.5).abs() < 1e-4, "{:?}", o.fractions);
+                let angle = flow.angle(x, z).unwrap();
+                assert!((angle - FRAC_PI_4 / 2.0).abs() < 1e-4, "{angle}");
+            }
+        }
+    }
+
+    #[test]
+    fn accumulation_at_outlets_sums_to_the_cell_count() {
+        let terrain = map(24, 20, |x, z| {
+            (x * 0.7).sin() + (z * 0.45).cos() + 0.3 * (x * z * 0.1).sin() + 0.02 * x
+        });
+        for routing in [FlowRouting::D8, FlowRouting::DInfinity] {
+            let flow = FlowDirections::new(&terrain, routing);
+            let accumulation = flow.accumulation();
+            let at_outlets: f32 = flow
+                .outflows()
+                .iter()
+                .zip(accumulation.iter())
+                .filter(|(o, _)| o.is_sink())
+                .map(|(_, a)| a)
+                .sum();
+            assert!(
+                (at_outlets - terrain.len() as f32).abs() < 1e-2,
+                "{routing:?}: {at_outlets}"
+            );
+        }
+    }
+}
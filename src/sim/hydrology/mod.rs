@@ -0,0 +1,3 @@
+pub mod depression;
+pub mod flow;
+pub mod network;
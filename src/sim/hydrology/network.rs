@@ -0,0 +1,267 @@
+use anyhow::{Result, ensure};
+use serde::Serialize;
+
+use super::flow::FlowDirections;
+use crate::sim::r#gen::lib::HeightMap;
+
+// one link of the drainage network, from a source or confluence down to the
+// next confluence or outlet. Points are cell centres as (x, z)
+#[derive(Clone, Debug, Serialize)]
+pub struct Stream {
+    pub points: Vec<[f32; 2]>,
+    pub order: u32,
+    // index of the stream this one flows into, None at an outlet
+    pub downstream: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamNetwork {
+    pub width: usize,
+    pub height: usize,
+    pub streams: Vec<Stream>,
+    // strahler order of every cell, 0 off the network
+    #[serde(skip)]
+    pub orders: Vec<u32>,
+}
+
+// every cell labelled with the outlet it drains to, which follows each cell's
+// main receiver so D-infinity splits are assigned to their larger share
+#[derive(Clone, Debug)]
+pub struct Basins {
+    pub width: usize,
+    pub height: usize,
+    pub labels: Vec<u32>,
+    // cell index of each basin's outlet, indexed by label
+    pub outlets: Vec<usize>,
+}
+
+impl StreamNetwork {
+    // cells whose accumulation reaches threshold form the network
+    pub fn extract(
+        flow: &FlowDirections,
+        accumulation: &HeightMap,
+        threshold: f32,
+    ) -> Result<StreamNetwork> {
+        let (width, height) = (flow.width(), flow.height());
+        ensure!(
+            accumulation.width() == width && accumulation.height() == height,
+            "Accumulation is {}x{} but flow directions are {}x{}",
+            accumulation.width(),
+            accumulation.height(),
+            width,
+            height
+        );
+
+        let on_stream: Vec<bool> = accumulation.iter().map(|&a| a >= threshold).collect();
+        // a d-infinity main receiver can fall below the threshold, ending the stream
+        let receiver = |i: usize| flow.outflows()[i].main_receiver().filter(|&r| on_stream[r]);
+
+        let mut donors = vec![0u32; on_stream.len()];
+        for i in (0..on_stream.len()).filter(|&i| on_stream[i]) {
+            if let Some(r) = receiver(i) {
+                donors[r] += 1;
+            }
+        }
+
+        // strahler order, rising by one where two streams of the highest order meet
+        let mut orders = vec![0u32; on_stream.len()];
+        let mut highest = vec![(0u32, 0u32); on_stream.len()]; // (order, streams of it)
+        for i in flow.upstream_order() {
+            if !on_stream[i] {
+                continue;
+            }
+            orders[i] = match highest[i] {
+                (0, _) => 1,
+                (order, 1) => order,
+                (order, _) => order + 1,
+            };
+            if let Some(r) = receiver(i) {
+                let (order, count) = &mut highest[r];
+                if orders[i] > *order {
+                    (*order, *count) = (orders[i], 1);
+                } else if orders[i] == *order {
+                    *count += 1;
+                }
+            }
+        }
+
+        // a stream starts at every source and every cell below a confluence
+        let starts_stream = |i: usize| on_stream[i] && donors[i] != 1;
+        let centre = |i: usize| [(i % width) as f32, (i / width) as f32];
+
+        let mut streams = Vec::new();
+        let mut stream_of = vec![usize::MAX; on_stream.len()];
+        for start in (0..on_stream.len()).filter(|&i| starts_stream(i)) {
+            stream_of[start] = streams.len();
+            let mut points = vec![centre(start)];
+            let mut cell = start;
+            while let Some(next) = receiver(cell) {
+                points.push(centre(next));
+                if starts_stream(next) {
+                    break;
+                }
+                cell = next;
+            }
+
+            streams.push(Stream {
+                points,
+                order: orders[start],
+                downstream: None,
+            });
+        }
+
+        // link each stream to the one starting where it ends
+        for (s, start) in (0..on_stream.len())
+            .filter(|&i| starts_stream(i))
+            .enumerate()
+        {
+            let mut cell = start;
+            while let Some(next) = receiver(cell) {
+                if starts_stream(next) {
+                    streams[s].downstream = Some(stream_of[next]);
+                    break;
+                }
+                cell = next;
+            }
+        }
+
+        Ok(StreamNetwork {
+            width,
+            height,
+            streams,
+            orders,
+        })
+    }
+
+    // strahler orders as a grid, 0 off the network
+    pub fn order_map(&self) -> HeightMap {
+        let orders = self.orders.iter().map(|&o| o as f32).collect();
+        HeightMap::from_vec(self.width, self.height, orders).unwrap()
+    }
+}
+
+impl Basins {
+    pub fn new(flow: &FlowDirections) -> Basins {
+        let outflows = flow.outflows();
+        let mut labels = vec![u32::MAX; outflows.len()];
+        let mut outlets = Vec::new();
+
+        // receivers come after their donors, so walking backwards labels every
+        // receiver before the cells draining into it
+        for i in flow.upstream_order().into_iter().rev() {
+            labels[i] = match outflows[i].main_receiver() {
+                Some(r) => labels[r],
+                None => {
+                    outlets.push(i);
+                    outlets.len() as u32 - 1
+                }
+            };
+        }
+
+        Basins {
+            width: flow.width(),
+            height: flow.height(),
+            labels,
+            outlets,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outlets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outlets.is_empty()
+    }
+
+    pub fn label(&self, x: usize, z: usize) -> u32 {
+        self.labels[z * self.width + x]
+    }
+
+    // labels as a grid
+    pub fn label_map(&self) -> HeightMap {
+        let labels = self.labels.iter().map(|&l| l as f32).collect();
+        HeightMap::from_vec(self.width, self.height, labels).unwrap()
+    }
+
+    // 1 inside the basin draining through (x, z) and 0 elsewhere, a mask for
+    // HeightMap::blend and multiplication
+    pub fn watershed(flow: &FlowDirections, x: usize, z: usize) -> HeightMap {
+        let outflows = flow.outflows();
+        let target = z * flow.width() + x;
+        let mut inside = vec![0.0f32; outflows.len()];
+        inside[target] = 1.0;
+
+        for i in flow.upstream_order().into_iter().rev() {
+            if i != target && outflows[i].iter().any(|(r, _)| inside[r] > 0.0) {
+                inside[i] = 1.0;
+            }
+        }
+
+        HeightMap::from_vec(flow.width(), flow.height(), inside).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::hydrology::flow::FlowRouting;
+
+    fn network(map: &HeightMap, threshold: f32) -> (FlowDirections, StreamNetwork) {
+        let flow = FlowDirections::new(map, FlowRouting::D8);
+        let network = StreamNetwork::extract(&flow, &flow.accumulation(), threshold).unwrap();
+        (flow, network)
+    }
+
+    #[test]
+    fn two_first_order_streams_make_a_second_order_one() {
+        let map = HeightMap::from_rows(&[vec![3.0, 2.0, 1.0, 0.0, 1.0, 2.0]]).unwrap();
+        let (_, network) = network(&map, 1.0);
+        assert_eq!(network.orders, [1, 1, 1, 2, 1, 1]);
+
+        // one stream from each source, both flowing into the outlet's stream
+        assert_eq!(network.streams.len(), 3);
+        let outlet = network.streams.iter().position(|s| s.order == 2).unwrap();
+        assert_eq!(network.streams[outlet].points, [[3.0, 0.0]]);
+        assert_eq!(network.streams[outlet].downstream, None);
+        for (s, stream) in network.streams.iter().enumerate() {
+            if s != outlet {
+                assert_eq!(stream.order, 1);
+                assert_eq!(stream.downstream, Some(outlet));
+                assert_eq!(stream.points.last(), Some(&[3.0, 0.0]));
+            }
+        }
+    }
+
+    #[test]
+    fn order_only_rises_where_equal_orders_meet() {
+        let data = (0..48 * 48)
+            .map(|i| {
+                let (x, z) = ((i % 48) as f32, (i / 48) as f32);
+                (x * 0.3).sin() * (z * 0.25).cos() + 0.05 * (x * 1.7 + z * 2.3).sin() + 0.01 * z
+            })
+            .collect();
+        let map = HeightMap::from_vec(48, 48, data).unwrap();
+        let (flow, network) = network(&map, 3.0);
+        assert!(network.orders.iter().any(|&o| o >= 2));
+
+        for i in (0..map.len()).filter(|&i| network.orders[i] > 0) {
+            let donors: Vec<u32> = (0..map.len())
+                .filter(|&j| network.orders[j] > 0)
+                .filter(|&j| flow.outflows()[j].main_receiver() == Some(i))
+                .map(|j| network.orders[j])
+                .collect();
+            let highest = donors.iter().copied().max().unwrap_or(0);
+            let meeting = donors.iter().filter(|&&o| o == highest).count();
+            let expected = match (highest, meeting) {
+                (0, _) => 1,
+                (order, 1) => order,
+                (order, _) => order + 1,
+            };
+            assert_eq!(
+                network.orders[i], expected,
+                "cell {i} with donors {donors:?}"
+            );
+        }
+    }
+}
@@ -1,8 +1,17 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sim::terrain::Terrain;
+
+#[derive(Default)]
 pub struct MaterialRegistry {
     materials: Vec<MaterialProperties>,
+    names: Vec<String>, // indexed by id like materials
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct MaterialProperties {
     pub erosion: f32,
     pub cohesion: f32,
@@ -11,18 +20,134 @@ pub struct MaterialProperties {
     pub mass: f32,
 }
 
-impl Default for MaterialRegistry {
-    fn default() -> Self {
-        MaterialRegistry {
-            materials: Vec::new(),
-        }
-    }
+// on disk layout of a registry, materials are listed in id order
+#[derive(Serialize, Deserialize)]
+struct MaterialFile {
+    materials: Vec<NamedMaterial>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NamedMaterial {
+    name: String,
+    #[serde(flatten)]
+    properties: MaterialProperties,
 }
 
 impl MaterialRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // registering an existing name replaces its properties and keeps its id
+    pub fn register(&mut self, name: &str, properties: MaterialProperties) -> u16 {
+        if let Some(id) = self.id(name) {
+            self.materials[id as usize] = properties;
+            return id;
+        }
+
+        assert!(
+            self.materials.len() <= u16::MAX as usize,
+            "Material registry is full"
+        );
+        let id = self.materials.len() as u16;
+        self.materials.push(properties);
+        self.names.push(name.to_string());
+
+        id
+    }
+
     pub fn get(&self, id: u16) -> &MaterialProperties {
         &self.materials[id as usize]
     }
+
+    pub fn try_get(&self, id: u16) -> Option<&MaterialProperties> {
+        self.materials.get(id as usize)
+    }
+
+    // every material in the terrain must be registered before erosion looks it up
+    pub fn ensure_registered(&self, terrain: &Terrain) -> anyhow::Result<()> {
+        for (i, cell) in terrain.cells.iter().enumerate() {
+            for layer in cell.layers() {
+                anyhow::ensure!(
+                    self.try_get(layer.material_id()).is_some(),
+                    "Cell ({}, {}) has unregistered material id {}",
+                    i % terrain.width,
+                    i / terrain.width,
+                    layer.material_id()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // registries hold a handful of materials, so a scan beats keeping a map in sync
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.names.iter().position(|n| n == name).map(|id| id as u16)
+    }
+
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.names.get(id as usize).map(|n| n.as_str())
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&MaterialProperties> {
+        self.id(name).map(|id| self.get(id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str, &MaterialProperties)> {
+        self.names
+            .iter()
+            .zip(self.materials.iter())
+            .enumerate()
+            .map(|(id, (name, properties))| (id as u16, name.as_str(), properties))
+    }
+
+    pub fn from_yaml(text: &str) -> anyhow::Result<Self> {
+        let file: MaterialFile = serde_yaml::from_str(text)?;
+        let mut registry = MaterialRegistry::new();
+        for m in file.materials {
+            anyhow::ensure!(
+                registry.id(&m.name).is_none(),
+                "Material '{}' is defined more than once",
+                m.name
+            );
+            registry.register(&m.name, m.properties);
+        }
+
+        Ok(registry)
+    }
+
+    pub fn to_yaml(&self) -> anyhow::Result<String> {
+        let file = MaterialFile {
+            materials: self
+                .iter()
+                .map(|(_, name, properties)| NamedMaterial {
+                    name: name.to_string(),
+                    properties: *properties,
+                })
+                .collect(),
+        };
+
+        Ok(serde_yaml::to_string(&file)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::from_yaml(&text)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_yaml()?)?;
+        Ok(())
+    }
 }
 
 impl Default for MaterialProperties {
@@ -1,3 +1,6 @@
+pub mod erosion;
 pub mod r#gen;
+pub mod grid;
+pub mod hydrology;
 pub mod materials;
 pub mod terrain;
@@ -31,6 +31,33 @@ impl Terrain {
         }
     }
 
+    // a single layer of material_id per cell. Layers are thicknesses stacked on
+    // the bedrock at 0, so negative heights cannot be represented and are an
+    // error, offset the heights first. Cells at 0 are left empty
+    pub fn from_heights(
+        width: usize,
+        height: usize,
+        heights: &[f32],
+        material_id: u16,
+    ) -> anyhow::Result<Self> {
+        assert!(heights.len() == width * height);
+        let mut terrain = Terrain::new(width, height);
+        for (i, (cell, &h)) in terrain.cells.iter_mut().zip(heights).enumerate() {
+            anyhow::ensure!(
+                h >= 0.0,
+                "Cell ({}, {}) has negative height {}",
+                i % width,
+                i / width,
+                h
+            );
+            if h > 0.0 {
+                cell.deposit(h, material_id);
+            }
+        }
+
+        Ok(terrain)
+    }
+
     #[inline]
     pub fn cell(&self, x: usize, z: usize) -> &Cell {
         &self.cells[z * self.width + x]
@@ -44,6 +71,19 @@ impl Terrain {
     pub fn extract_heights(&self) -> Vec<f32> {
         self.cells.iter().map(|c| c.total_height()).collect()
     }
+
+    // deposits or erodes each cell's surface so its total height matches `heights`
+    pub fn apply_heights(&mut self, heights: &[f32]) {
+        assert!(heights.len() == self.cells.len());
+        for (cell, &h) in self.cells.iter_mut().zip(heights) {
+            let diff = h - cell.total_height();
+            if diff > 0.0 {
+                cell.deposit(diff, cell.surface_material().unwrap_or(0));
+            } else if diff < 0.0 {
+                cell.erode(-diff);
+            }
+        }
+    }
 }
 
 impl Cell {
@@ -67,6 +107,11 @@ impl Cell {
         self.layers.is_empty()
     }
 
+    // bottom layer first
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
     pub fn deposit(&mut self, thickness: f32, material_id: u16) {
         if let Some(top) = self.layers.last_mut() {
             if top.material_id == material_id {
@@ -115,7 +160,15 @@ impl Layer {
     }
 }
 
-impl Layer {}
+impl Layer {
+    pub fn thickness(&self) -> f32 {
+        self.thickness
+    }
+
+    pub fn material_id(&self) -> u16 {
+        self.material_id
+    }
+}
 
 impl Default for Layer {
     fn default() -> Self {
@@ -125,3 +178,20 @@ impl Default for Layer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heights_round_trip() {
+        let heights = [0.0, 1.5, 0.25, 3.0, 0.0, 2.0];
+        let terrain = Terrain::from_heights(3, 2, &heights, 1).unwrap();
+        assert_eq!(terrain.extract_heights(), heights);
+    }
+
+    #[test]
+    fn negative_heights_are_an_error() {
+        assert!(Terrain::from_heights(2, 1, &[1.0, -0.5], 0).is_err());
+    }
+}